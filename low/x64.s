@@ -71,6 +71,34 @@ write_gdtr:
 lgdt [rdi]
 ret
 
+# rdi = code selector, rsi = data selector
+# Note: FS and GS are not reloaded, because that would clear their base addresses
+.global reload_segments
+reload_segments:
+mov ds, si
+mov es, si
+mov ss, si
+push rdi
+lea rax, [rip + reload_segments_return]
+push rax
+retfq
+reload_segments_return:
+ret
+
+.global write_task_register
+write_task_register:
+ltr di
+ret
+
+# Calls the function on another stack, rdi = function, rsi = top of the stack
+# Note: The function must not return, because nothing is left on the new stack to return to
+.global call_on_stack
+call_on_stack:
+mov rsp, rsi
+xor ebp, ebp # End the frame pointer chain, so that stack traces stop at the function
+call rdi
+ud2
+
 .global write_fs_base
 write_fs_base:
 wrfsbase rdi
@@ -92,6 +120,83 @@ mov rax, cr3
 mov cr3, rax
 ret
 
+.global read_cpuid
+read_cpuid:
+push rbx
+mov r8, rdx
+mov eax, edi
+mov ecx, esi
+cpuid
+mov [r8], eax
+mov [r8 + 4], ebx
+mov [r8 + 8], ecx
+mov [r8 + 12], edx
+pop rbx
+ret
+
+# Switches to 5-level paging, rdi = physical address of the top-level table
+# Note: The paging mode can only be changed while paging is disabled, which is only possible outside of 64-bit mode.
+# The code, the stack and the table must be identity mapped below 4 GiB and interrupts must be disabled.
+.global enable_five_level_paging
+enable_five_level_paging:
+push rbx
+push rbp
+push r12
+push r13
+push r14
+push r15
+sgdt [rip + five_level_paging_saved_gdtr]
+mov [rip + five_level_paging_saved_rsp], rsp
+mov ax, ss
+mov [rip + five_level_paging_saved_ss], ax
+mov ax, cs
+mov [rip + five_level_paging_saved_cs], ax
+lgdt [rip + five_level_paging_gdtr]
+lea rsp, [rip + five_level_paging_stack_top]
+push 0x18  # 32-bit code segment
+lea rax, [rip + five_level_paging_protected_mode]
+push rax
+retfq
+
+.code32
+five_level_paging_protected_mode:
+mov ax, 0x10
+mov ss, ax
+mov eax, cr0
+and eax, 0x7fffffff  # Disabling paging also leaves long mode
+mov cr0, eax
+mov eax, cr4
+or eax, 0x1000       # LA57
+mov cr4, eax
+mov cr3, edi
+mov eax, cr0
+or eax, 0x80000000   # EFER.LME is still set, so enabling paging enters long mode again
+mov cr0, eax
+push 0x8             # 64-bit code segment
+push offset five_level_paging_long_mode
+retf
+
+.code64
+five_level_paging_long_mode:
+lgdt [rip + five_level_paging_saved_gdtr]
+mov ax, [rip + five_level_paging_saved_ss]
+mov ss, ax
+mov rsp, [rip + five_level_paging_saved_rsp]
+# Note: The original table is the one the loader set up, so its code segment is not necessarily the kernel's
+movzx eax, word ptr [rip + five_level_paging_saved_cs]
+push rax  # Reload the code segment from the original table
+lea rax, [rip + five_level_paging_return]
+push rax
+retfq
+five_level_paging_return:
+pop r15
+pop r14
+pop r13
+pop r12
+pop rbp
+pop rbx
+ret
+
 .global interrupts_enable
 interrupts_enable:
 sti
@@ -160,6 +265,11 @@ registers_rip:
 mov rax, [rsp]
 ret
 
+.global registers_rbp
+registers_rbp:
+mov rax, rbp
+ret
+
 .align 32
 .global interrupts_entry
 interrupts_entry:
@@ -169,8 +279,29 @@ cli # Disable interrupts
 # Save the interrupt stack pointer and load the kernel stack pointer
 # Note: Each thread has its own kernel stack for saving the state in kernel easily
 mov [gs:0], rsp
+
+# A stack overflow is reported on the interrupt stack, because the kernel stack may be the one that overflowed
+push rax
+mov rax, [rsp+8] # Interrupt number
+cmp rax, 14 # Page fault
+je interrupts_entry_check_fault_address
+cmp rax, 8 # Double fault
+jne interrupts_entry_switch_stack
+
+interrupts_entry_check_fault_address:
+mov rax, cr2
+shr rax, 39
+cmp rax, 0x1ffff81 # Kernel stack region (KERNEL_STACK_REGION_BASE >> 39)
+jne interrupts_entry_switch_stack
+
+pop rax
+jmp interrupts_entry_save
+
+interrupts_entry_switch_stack:
+pop rax
 mov rsp, [gs:8]
 
+interrupts_entry_save:
 # Interrupt stack has data we do not have in this new stack, reserve space for it and copy it later
 sub rsp, 56
 
@@ -975,6 +1106,33 @@ color_area:
         .long   65535
         .long   65535
 
+# Note: The tables are written at runtime, so they can not be in the read-only text section
+.section .data
+
+# Temporary descriptors for switching the paging mode, the 64-bit code segment matches the kernel code selector
+.align 16
+five_level_paging_gdt:
+.quad 0
+.quad 0x00af9a000000ffff  # 64-bit code
+.quad 0x00cf92000000ffff  # Data
+.quad 0x00cf9a000000ffff  # 32-bit code
+five_level_paging_gdtr:
+.word five_level_paging_gdtr - five_level_paging_gdt - 1
+.quad five_level_paging_gdt
+five_level_paging_saved_gdtr:
+.zero 10
+five_level_paging_saved_ss:
+.word 0
+five_level_paging_saved_cs:
+.word 0
+.align 8
+five_level_paging_saved_rsp:
+.quad 0
+.align 16
+five_level_paging_stack:
+.zero 0x100
+five_level_paging_stack_top:
+
 .align 0x1000
 .global interrupts_tables
 interrupts_tables:
@@ -1,9 +1,78 @@
-use crate::serial_write;
+#[cfg(not(test))]
+use crate::{
+    low::x64::registers_rbp,
+    memory::{mapper, VirtualAddress},
+    serial_write
+};
 
+#[cfg(not(test))]
 pub fn write(args: ::core::fmt::Arguments) {
     serial_write!("{}", args);
 }
 
+// Tests run as a regular process on the host, which can not access the serial port
+#[cfg(test)]
+pub fn write(args: ::core::fmt::Arguments) {
+    std::print!("{}", args);
+}
+
+// Collects the return addresses of the callers into the slice, skipping the innermost frames, and returns how many were found
+#[cfg(not(test))]
+pub fn capture_return_addresses(skip: usize, addresses: &mut [u64]) -> usize {
+    // The frame of this function is the innermost one
+    walk_frames(unsafe { registers_rbp() }, skip, addresses)
+}
+
+// Collects the return addresses starting from the frame, for example the frame of interrupted code
+#[cfg(not(test))]
+pub fn capture_return_addresses_from(frame: u64, addresses: &mut [u64]) -> usize {
+    walk_frames(frame, 0, addresses)
+}
+
+// Note: The kernel is built with frame pointers, so each frame starts with the caller's frame pointer and the return address.
+#[cfg(not(test))]
+fn walk_frames(mut frame: u64, skip: usize, addresses: &mut [u64]) -> usize {
+    let mut count = 0;
+    let mut depth = 0;
+
+    while count < addresses.len() {
+        // The outermost frame was created by the bootloader, so the chain must be validated before following it
+        let is_mapped = |address: u64| mapper::translate(VirtualAddress::new(address as usize)).is_some();
+
+        if frame == 0 || !frame.is_multiple_of(8) || !is_mapped(frame) || !is_mapped(frame + 8) {
+            break;
+        }
+
+        let (next, return_address) = unsafe { (*(frame as *const u64), *((frame + 8) as *const u64)) };
+
+        if depth >= skip {
+            addresses[count] = return_address;
+            count += 1;
+        }
+
+        // Stacks grow downwards, so the frames of the callers are above
+        if next <= frame {
+            break;
+        }
+
+        frame = next;
+        depth += 1;
+    }
+
+    count
+}
+
+// Tests run without frame pointers, so there is nothing to follow
+#[cfg(test)]
+pub fn capture_return_addresses(_skip: usize, _addresses: &mut [u64]) -> usize {
+    0
+}
+
+#[cfg(test)]
+pub fn capture_return_addresses_from(_frame: u64, _addresses: &mut [u64]) -> usize {
+    0
+}
+
 #[macro_export]
 macro_rules! debug_write {
     ($($arg:tt)*) => {
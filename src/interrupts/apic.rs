@@ -1,8 +1,11 @@
 use crate::{debug_write_line, interrupts::ioapic::IOAPIC, low::{ports, x64::{read_msr, write_msr}}, memory::{mapper, PhysicalAddress, paging_table::PagingFlags}};
-use core::{mem, slice, ptr};
+use core::{mem, slice, ptr, sync::atomic::{AtomicPtr, Ordering}};
 
 use super::MAX_INTERRUPT_COUNT;
 
+// Note: Spurious interrupts usually mean an interrupt whose origin is unknown, they must not be acknowledged
+pub const SPURIOUS_INTERRUPT_NUMBER: u64 = (MAX_INTERRUPT_COUNT - 1) as u64;
+
 const MAX_LOCAL_APIC_COUNT: usize = 256;
 
 const APIC_BASE_MSR: usize = 0x1B;
@@ -11,10 +14,19 @@ const APIC_BASE_MSR_ENABLE: u64 = 0x800;
 const SPURIOUS_INTERRUPT_VECTOR_REGISTER_OFFSET: usize = 0xf0;
 const ENABLE_APIC_FLAG: u32 = 0x100;
 
+const END_OF_INTERRUPT_REGISTER_OFFSET: usize = 0xb0;
+const INTERRUPT_COMMAND_REGISTER_OFFSET: usize = 0x300;
+const INTERRUPT_COMMAND_DELIVERY_PENDING: u32 = 1 << 12;
+const INTERRUPT_COMMAND_LEVEL_ASSERT: u32 = 1 << 14;
+const INTERRUPT_COMMAND_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+// Note: The local APIC registers are at the same address on every processor, but each processor sees its own
+static LOCAL_APIC_REGISTERS: AtomicPtr<u32> = AtomicPtr::new(ptr::null_mut());
+
 #[repr(C)]
 pub struct SDTHeader {
     signature: u32,
-    length: u32,
+    pub length: u32,
     revision: u8,
     checksum: u8,
     oem_id: [u8; 6],
@@ -257,9 +269,7 @@ unsafe fn enable() {
 unsafe fn enable_interrupts(local_apic_registers: *mut u32) {
     let register = local_apic_registers.byte_add(SPURIOUS_INTERRUPT_VECTOR_REGISTER_OFFSET);
 
-    // Map spurious interrupts to a specific interrupt number?
-    // Note: Spurious interrupt usually means an interrupt whose origin is unknown
-    let spurious_interrupt_number = (MAX_INTERRUPT_COUNT - 1) as u32;
+    let spurious_interrupt_number = SPURIOUS_INTERRUPT_NUMBER as u32;
 
     let mut value = *register;
     value |= spurious_interrupt_number;
@@ -281,6 +291,7 @@ pub unsafe fn initialize_unsafe(rsdp_physical_address: PhysicalAddress) {
 
     enable();
     enable_interrupts(apic_info.local_apic_registers);
+    LOCAL_APIC_REGISTERS.store(apic_info.local_apic_registers, Ordering::Release);
 
     let ioapic = IOAPIC::new(apic_info.ioapic_registers);
 
@@ -288,6 +299,39 @@ pub unsafe fn initialize_unsafe(rsdp_physical_address: PhysicalAddress) {
     ioapic.redirect(1, 0);
 }
 
+// Acknowledges the interrupt that is being handled, so that the local APIC can deliver the next one
+pub fn end_of_interrupt() {
+    let local_apic_registers = LOCAL_APIC_REGISTERS.load(Ordering::Acquire);
+
+    if local_apic_registers.is_null() {
+        return;
+    }
+
+    unsafe {
+        local_apic_registers.byte_add(END_OF_INTERRUPT_REGISTER_OFFSET).write_volatile(0);
+    }
+}
+
+// Sends an inter-processor interrupt to all the other processors
+pub fn send_interrupt_to_others(interrupt_number: u8) {
+    let local_apic_registers = LOCAL_APIC_REGISTERS.load(Ordering::Acquire);
+    assert!(!local_apic_registers.is_null(), "APIC: Local APIC is not initialized");
+
+    unsafe {
+        let register = local_apic_registers.byte_add(INTERRUPT_COMMAND_REGISTER_OFFSET);
+
+        // The previous interrupt must be sent before the command register can be reused
+        while register.read_volatile() & INTERRUPT_COMMAND_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+
+        // Note: The destination in the high half of the register is ignored when a shorthand is used
+        register.write_volatile(
+            interrupt_number as u32 | INTERRUPT_COMMAND_LEVEL_ASSERT | INTERRUPT_COMMAND_ALL_EXCLUDING_SELF
+        );
+    }
+}
+
 pub fn initialize(rsdp_physical_address: PhysicalAddress) {
     unsafe {
         initialize_unsafe(rsdp_physical_address);
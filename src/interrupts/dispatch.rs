@@ -0,0 +1,215 @@
+use core::{mem, sync::atomic::{AtomicUsize, Ordering}};
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::debug_write_line;
+
+use super::{ioapic, RegisterState, INTERRUPT_BASE, MAX_INTERRUPT_COUNT};
+
+// Returns whether the interrupt was handled, so that handlers of a shared vector can tell whether their device raised it
+pub type InterruptHandler = fn(&mut RegisterState) -> bool;
+
+pub const MAX_SHARED_HANDLER_COUNT: usize = 4;
+
+// Vectors that drivers can allocate, the ones below are used by the IOAPIC redirections and the ones above by the kernel
+pub const DYNAMIC_VECTOR_START: u8 = INTERRUPT_BASE + ioapic::REDIRECTION_ENTRY_COUNT;
+pub const DYNAMIC_VECTOR_END: u8 = 0xf0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HandlerError {
+    VectorInUse, // The vector has a handler that does not share it
+    TooManyHandlers,
+    NotRegistered
+}
+
+// Identifies a registered handler, so that it can be unregistered
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HandlerId {
+    vector: u8,
+    slot: usize
+}
+
+impl HandlerId {
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+}
+
+#[derive(Clone, Copy)]
+struct VectorState {
+    handler_count: usize,
+    shared: bool, // Whether the registered handlers share the vector
+    allocated: bool // Given to a driver by allocate_vector
+}
+
+// The handlers of each vector, an empty slot is zero.
+// Note: Dispatching reads the slots without locking, so that an interrupt never waits for a registration.
+static HANDLERS: [[AtomicUsize; MAX_SHARED_HANDLER_COUNT]; MAX_INTERRUPT_COUNT] =
+    [const { [const { AtomicUsize::new(0) }; MAX_SHARED_HANDLER_COUNT] }; MAX_INTERRUPT_COUNT];
+
+lazy_static! {
+    // Serializes the registrations.
+    // Note: Handlers must not register handlers, because the interrupted code may hold this lock.
+    static ref vectors: Mutex<[VectorState; MAX_INTERRUPT_COUNT]> = {
+        Mutex::new([VectorState { handler_count: 0, shared: false, allocated: false }; MAX_INTERRUPT_COUNT])
+    };
+}
+
+// Adds a handler to the vector. A shared handler is called with the other shared handlers of the vector,
+// an exclusive one requires the vector to have no other handlers.
+pub fn register_handler(vector: u8, handler: InterruptHandler, shared: bool) -> Result<HandlerId, HandlerError> {
+    let mut vectors_lock = vectors.lock();
+    let state = &mut vectors_lock[vector as usize];
+
+    if state.handler_count > 0 && !(state.shared && shared) {
+        return Err(HandlerError::VectorInUse);
+    }
+
+    let slots = &HANDLERS[vector as usize];
+    let slot = slots
+        .iter()
+        .position(|slot| slot.load(Ordering::Relaxed) == 0)
+        .ok_or(HandlerError::TooManyHandlers)?;
+
+    slots[slot].store(handler as usize, Ordering::Release);
+    state.handler_count += 1;
+    state.shared = shared;
+
+    Ok(HandlerId { vector, slot })
+}
+
+// Todo: Another processor may still be running the handler when this returns
+pub fn unregister_handler(id: HandlerId) -> Result<(), HandlerError> {
+    let mut vectors_lock = vectors.lock();
+    let slot = &HANDLERS[id.vector as usize][id.slot];
+
+    if slot.swap(0, Ordering::AcqRel) == 0 {
+        return Err(HandlerError::NotRegistered);
+    }
+
+    vectors_lock[id.vector as usize].handler_count -= 1;
+
+    Ok(())
+}
+
+// Finds a vector that nothing else uses, returns None if all of them are in use
+pub fn allocate_vector() -> Option<u8> {
+    let mut vectors_lock = vectors.lock();
+
+    let vector = (DYNAMIC_VECTOR_START..DYNAMIC_VECTOR_END).find(|vector| {
+        let state = &vectors_lock[*vector as usize];
+        !state.allocated && state.handler_count == 0
+    })?;
+
+    vectors_lock[vector as usize].allocated = true;
+    debug_write_line!("Interrupts: Allocated vector {:#X}", vector);
+
+    Some(vector)
+}
+
+// Note: The handlers of the vector must be unregistered first
+pub fn free_vector(vector: u8) {
+    let mut vectors_lock = vectors.lock();
+    let state = &mut vectors_lock[vector as usize];
+
+    assert!(state.allocated, "Interrupts: Freed vector {:#X}, which was not allocated", vector);
+    assert!(state.handler_count == 0, "Interrupts: Freed vector {:#X}, which still has handlers", vector);
+
+    state.allocated = false;
+}
+
+// Calls the handlers of the interrupt, returns whether any of them handled it.
+// Note: All the handlers of a shared vector are called, because more than one device may be waiting.
+pub fn dispatch(registers: &mut RegisterState) -> bool {
+    let mut handled = false;
+
+    for slot in &HANDLERS[registers.interrupt_number as usize] {
+        let handler = slot.load(Ordering::Acquire);
+
+        if handler == 0 {
+            continue;
+        }
+
+        let handler = unsafe { mem::transmute::<usize, InterruptHandler>(handler) };
+        handled |= handler(registers);
+    }
+
+    handled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Note: The tests run in parallel on the same table, so each of them uses its own vectors
+    fn dispatch_vector(vector: u8) -> (bool, u64) {
+        let mut registers: RegisterState = unsafe { mem::zeroed() };
+        registers.interrupt_number = vector as u64;
+
+        let handled = dispatch(&mut registers);
+        (handled, registers.rax)
+    }
+
+    fn count(registers: &mut RegisterState) -> bool {
+        registers.rax += 1;
+        true
+    }
+
+    fn ignore(_registers: &mut RegisterState) -> bool {
+        false
+    }
+
+    #[test]
+    fn shared_vectors_call_all_handlers() {
+        let first = register_handler(0x80, count, true).unwrap();
+        let second = register_handler(0x80, ignore, true).unwrap();
+        let third = register_handler(0x80, count, true).unwrap();
+
+        assert_eq!(dispatch_vector(0x80), (true, 2));
+
+        unregister_handler(first).unwrap();
+        unregister_handler(third).unwrap();
+        assert_eq!(dispatch_vector(0x80), (false, 0));
+
+        unregister_handler(second).unwrap();
+        assert_eq!(unregister_handler(second), Err(HandlerError::NotRegistered));
+    }
+
+    #[test]
+    fn exclusive_handlers_do_not_share() {
+        let id = register_handler(0x81, count, false).unwrap();
+        assert_eq!(register_handler(0x81, count, true), Err(HandlerError::VectorInUse));
+        assert_eq!(register_handler(0x81, count, false), Err(HandlerError::VectorInUse));
+
+        unregister_handler(id).unwrap();
+
+        let id = register_handler(0x81, count, true).unwrap();
+        assert_eq!(register_handler(0x81, count, false), Err(HandlerError::VectorInUse));
+        unregister_handler(id).unwrap();
+    }
+
+    #[test]
+    fn shared_vectors_have_limited_slots() {
+        for _ in 0..MAX_SHARED_HANDLER_COUNT {
+            register_handler(0x82, ignore, true).unwrap();
+        }
+
+        assert_eq!(register_handler(0x82, ignore, true), Err(HandlerError::TooManyHandlers));
+    }
+
+    #[test]
+    fn allocated_vectors_are_not_reused() {
+        let first = allocate_vector().unwrap();
+        let second = allocate_vector().unwrap();
+        assert!(first != second);
+        assert!((DYNAMIC_VECTOR_START..DYNAMIC_VECTOR_END).contains(&first));
+
+        let id = register_handler(first, count, false).unwrap();
+        assert_eq!(dispatch_vector(first), (true, 1));
+        unregister_handler(id).unwrap();
+
+        free_vector(first);
+        assert_eq!(allocate_vector(), Some(first));
+    }
+}
@@ -0,0 +1,369 @@
+use core::{mem, ptr};
+
+use crate::{
+    debug,
+    debug_write_line,
+    low::x64::{read_cr0, read_cr2, read_cr3, read_cr4},
+    memory::{kernel_stack, mapper, page_fault::PageFaultErrorCode, VirtualAddress}
+};
+
+use super::RegisterState;
+
+// The longest instruction is 15 bytes, so this always covers the faulting one
+const INSTRUCTION_BYTE_COUNT: usize = 16;
+const STACK_DUMP_COUNT: usize = 16;
+const BACKTRACE_DEPTH: usize = 16;
+
+// Bits of the error code that the segment related exceptions push
+const SELECTOR_EXTERNAL: u64 = 1 << 0; // Raised by an event outside of the program, like an interrupt
+const SELECTOR_TABLE_SHIFT: u64 = 1;
+const SELECTOR_INDEX_SHIFT: u64 = 3;
+
+// The ud2 instruction, which the compiler emits for code that must never be reached
+const UD2: [u8; 2] = [0x0f, 0x0b];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exception {
+    DivideError = 0,
+    Debug = 1,
+    NonMaskableInterrupt = 2,
+    Breakpoint = 3,
+    Overflow = 4,
+    BoundRangeExceeded = 5,
+    InvalidOpcode = 6,
+    DeviceNotAvailable = 7,
+    DoubleFault = 8,
+    CoprocessorSegmentOverrun = 9,
+    InvalidTSS = 10,
+    SegmentNotPresent = 11,
+    StackSegmentFault = 12,
+    GeneralProtection = 13,
+    PageFault = 14,
+    FloatingPointError = 16,
+    AlignmentCheck = 17,
+    MachineCheck = 18,
+    SIMDFloatingPoint = 19,
+    Virtualization = 20,
+    ControlProtection = 21,
+    HypervisorInjection = 28,
+    VMMCommunication = 29,
+    Security = 30
+}
+
+const EXCEPTIONS: [Exception; 24] = [
+    Exception::DivideError,
+    Exception::Debug,
+    Exception::NonMaskableInterrupt,
+    Exception::Breakpoint,
+    Exception::Overflow,
+    Exception::BoundRangeExceeded,
+    Exception::InvalidOpcode,
+    Exception::DeviceNotAvailable,
+    Exception::DoubleFault,
+    Exception::CoprocessorSegmentOverrun,
+    Exception::InvalidTSS,
+    Exception::SegmentNotPresent,
+    Exception::StackSegmentFault,
+    Exception::GeneralProtection,
+    Exception::PageFault,
+    Exception::FloatingPointError,
+    Exception::AlignmentCheck,
+    Exception::MachineCheck,
+    Exception::SIMDFloatingPoint,
+    Exception::Virtualization,
+    Exception::ControlProtection,
+    Exception::HypervisorInjection,
+    Exception::VMMCommunication,
+    Exception::Security
+];
+
+impl Exception {
+    // Returns None for the reserved vectors
+    pub fn from_interrupt_number(interrupt_number: u64) -> Option<Exception> {
+        EXCEPTIONS.into_iter().find(|exception| *exception as u64 == interrupt_number)
+    }
+
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Exception::DivideError => "#DE",
+            Exception::Debug => "#DB",
+            Exception::NonMaskableInterrupt => "NMI",
+            Exception::Breakpoint => "#BP",
+            Exception::Overflow => "#OF",
+            Exception::BoundRangeExceeded => "#BR",
+            Exception::InvalidOpcode => "#UD",
+            Exception::DeviceNotAvailable => "#NM",
+            Exception::DoubleFault => "#DF",
+            Exception::CoprocessorSegmentOverrun => "CSO",
+            Exception::InvalidTSS => "#TS",
+            Exception::SegmentNotPresent => "#NP",
+            Exception::StackSegmentFault => "#SS",
+            Exception::GeneralProtection => "#GP",
+            Exception::PageFault => "#PF",
+            Exception::FloatingPointError => "#MF",
+            Exception::AlignmentCheck => "#AC",
+            Exception::MachineCheck => "#MC",
+            Exception::SIMDFloatingPoint => "#XM",
+            Exception::Virtualization => "#VE",
+            Exception::ControlProtection => "#CP",
+            Exception::HypervisorInjection => "#HV",
+            Exception::VMMCommunication => "#VC",
+            Exception::Security => "#SX"
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Exception::DivideError => "Divide error",
+            Exception::Debug => "Debug exception",
+            Exception::NonMaskableInterrupt => "Non-maskable interrupt",
+            Exception::Breakpoint => "Breakpoint",
+            Exception::Overflow => "Overflow",
+            Exception::BoundRangeExceeded => "Bound range exceeded",
+            Exception::InvalidOpcode => "Invalid opcode",
+            Exception::DeviceNotAvailable => "Device not available",
+            Exception::DoubleFault => "Double fault",
+            Exception::CoprocessorSegmentOverrun => "Coprocessor segment overrun",
+            Exception::InvalidTSS => "Invalid TSS",
+            Exception::SegmentNotPresent => "Segment not present",
+            Exception::StackSegmentFault => "Stack segment fault",
+            Exception::GeneralProtection => "General protection fault",
+            Exception::PageFault => "Page fault",
+            Exception::FloatingPointError => "Floating point error",
+            Exception::AlignmentCheck => "Alignment check",
+            Exception::MachineCheck => "Machine check",
+            Exception::SIMDFloatingPoint => "SIMD floating point exception",
+            Exception::Virtualization => "Virtualization exception",
+            Exception::ControlProtection => "Control protection exception",
+            Exception::HypervisorInjection => "Hypervisor injection exception",
+            Exception::VMMCommunication => "VMM communication exception",
+            Exception::Security => "Security exception"
+        }
+    }
+
+    // Whether the processor pushes an error code, the interrupt stubs push padding for the others
+    pub fn has_error_code(self) -> bool {
+        matches!(
+            self,
+            Exception::DoubleFault |
+            Exception::InvalidTSS |
+            Exception::SegmentNotPresent |
+            Exception::StackSegmentFault |
+            Exception::GeneralProtection |
+            Exception::PageFault |
+            Exception::AlignmentCheck |
+            Exception::ControlProtection |
+            Exception::VMMCommunication |
+            Exception::Security
+        )
+    }
+}
+
+pub fn has_error_code(interrupt_number: u64) -> bool {
+    Exception::from_interrupt_number(interrupt_number).is_some_and(|exception| exception.has_error_code())
+}
+
+// Reads the value if its memory is mapped.
+// Note: The faulting code may have left anything in the registers, so following them must not fault again.
+fn read<T: Copy>(address: u64) -> Option<T> {
+    let end = address.checked_add(mem::size_of::<T>() as u64 - 1)?;
+    let is_mapped = |address: u64| mapper::translate(VirtualAddress::new(address as usize)).is_some();
+
+    if !is_mapped(address) || !is_mapped(end) {
+        return None;
+    }
+
+    Some(unsafe { ptr::read_unaligned(address as *const T) })
+}
+
+fn read_instruction(address: u64) -> ([u8; INSTRUCTION_BYTE_COUNT], usize) {
+    let mut bytes = [0; INSTRUCTION_BYTE_COUNT];
+    let mut count = 0;
+
+    // Note: The instruction may cross into an unmapped page, in which case the bytes before it are still useful
+    while count < INSTRUCTION_BYTE_COUNT {
+        let Some(byte) = read::<u8>(address + count as u64) else {
+            break;
+        };
+
+        bytes[count] = byte;
+        count += 1;
+    }
+
+    (bytes, count)
+}
+
+// Decodes the error code of the exceptions that report a segment selector
+fn describe_selector(error_code: u64) {
+    let table = match (error_code >> SELECTOR_TABLE_SHIFT) & 0b11 {
+        0 => "GDT",
+        2 => "LDT",
+        _ => "IDT"
+    };
+
+    let index = error_code >> SELECTOR_INDEX_SHIFT;
+    let source = if error_code & SELECTOR_EXTERNAL != 0 { "an external event" } else { "the program" };
+
+    debug_write_line!("Exception: Caused by {} entry {:#X}, raised by {}", table, index, source);
+}
+
+fn describe(exception: Exception, registers: &RegisterState) {
+    let error_code = registers.error_code;
+
+    match exception {
+        Exception::DivideError => {
+            debug_write_line!("Exception: Divided by zero or the quotient does not fit the destination");
+        },
+        Exception::InvalidOpcode => {
+            let (bytes, count) = read_instruction(registers.rip);
+
+            if count >= UD2.len() && bytes[..UD2.len()] == UD2 {
+                debug_write_line!("Exception: Reached ud2, which marks code that must never run");
+            } else {
+                debug_write_line!("Exception: The instruction is invalid or not supported by this processor");
+            }
+        },
+        Exception::InvalidTSS | Exception::SegmentNotPresent => describe_selector(error_code),
+        Exception::StackSegmentFault | Exception::GeneralProtection if error_code != 0 => describe_selector(error_code),
+        Exception::StackSegmentFault => {
+            debug_write_line!("Exception: Not caused by a segment, the stack address is probably not canonical");
+        },
+        Exception::GeneralProtection => {
+            debug_write_line!(
+                "Exception: Not caused by a segment, for example a non-canonical address or a privileged instruction"
+            );
+        },
+        Exception::PageFault => {
+            let address = unsafe { read_cr2() };
+            let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+            debug_write_line!("Exception: Accessed {:#X}, {:?}", address, error_code);
+        },
+        _ => {}
+    }
+}
+
+fn dump_registers(registers: &RegisterState) {
+    debug_write_line!(
+        "Exception: RAX={:#018X} RBX={:#018X} RCX={:#018X} RDX={:#018X}",
+        registers.rax, registers.rbx, registers.rcx, registers.rdx
+    );
+    debug_write_line!(
+        "Exception: RSI={:#018X} RDI={:#018X} RBP={:#018X} RSP={:#018X}",
+        registers.rsi, registers.rdi, registers.rbp, registers.interrupted_rsp
+    );
+    debug_write_line!(
+        "Exception: R8= {:#018X} R9= {:#018X} R10={:#018X} R11={:#018X}",
+        registers.r8, registers.r9, registers.r10, registers.r11
+    );
+    debug_write_line!(
+        "Exception: R12={:#018X} R13={:#018X} R14={:#018X} R15={:#018X}",
+        registers.r12, registers.r13, registers.r14, registers.r15
+    );
+    debug_write_line!(
+        "Exception: RIP={:#018X} RFLAGS={:#X} CS={:#X} SS={:#X}",
+        registers.rip, registers.rflags, registers.cs, registers.ss
+    );
+
+    unsafe {
+        debug_write_line!(
+            "Exception: CR0={:#X} CR2={:#X} CR3={:#X} CR4={:#X}",
+            read_cr0(), read_cr2(), read_cr3(), read_cr4()
+        );
+    }
+}
+
+fn dump_instruction(address: u64) {
+    let (bytes, count) = read_instruction(address);
+
+    if count == 0 {
+        debug_write_line!("Exception: Instruction at {:#X} is not mapped", address);
+        return;
+    }
+
+    debug_write_line!("Exception: Instruction bytes: {:02X?}", &bytes[..count]);
+}
+
+fn dump_stack(stack_pointer: u64) {
+    debug_write_line!("Exception: Stack:");
+
+    for index in 0..STACK_DUMP_COUNT {
+        let address = stack_pointer + (index * mem::size_of::<u64>()) as u64;
+
+        // Note: An overflowed stack points into its guard page, so nothing is shown
+        let Some(value) = read::<u64>(address) else {
+            debug_write_line!("Exception:     {:#018X}: Not mapped", address);
+            break;
+        };
+
+        debug_write_line!("Exception:     {:#018X}: {:#018X}", address, value);
+    }
+}
+
+fn dump_backtrace(frame: u64) {
+    let mut addresses = [0; BACKTRACE_DEPTH];
+    let count = debug::capture_return_addresses_from(frame, &mut addresses);
+
+    debug_write_line!("Exception: Backtrace: {:#X?}", &addresses[..count]);
+}
+
+// Shows everything we know about an exception that no handler resolved, and stops the kernel
+pub fn fail(registers: &RegisterState) -> ! {
+    let exception = Exception::from_interrupt_number(registers.interrupt_number);
+    let is_user = registers.cs & 0b11 == 3;
+
+    let (mnemonic, name) = exception.map_or(("#??", "Reserved exception"), |exception| (exception.mnemonic(), exception.name()));
+    let mode = if is_user { "user" } else { "kernel" };
+
+    debug_write_line!(
+        "Exception: {} {} ({}) in {} mode at {:#X}", mnemonic, name, registers.interrupt_number, mode, registers.rip
+    );
+
+    if let Some(exception) = exception {
+        if exception.has_error_code() {
+            debug_write_line!("Exception: Error code: {:#X}", registers.error_code);
+        }
+
+        describe(exception, registers);
+    }
+
+    dump_registers(registers);
+    dump_instruction(registers.rip);
+    dump_stack(registers.interrupted_rsp);
+    dump_backtrace(registers.rbp);
+
+    // A stack overflow causes a double fault if the page fault can not be delivered on the overflowed stack
+    if exception == Some(Exception::DoubleFault) {
+        let address = VirtualAddress::new(unsafe { read_cr2() } as usize);
+
+        if kernel_stack::is_overflow(address) {
+            panic!("Kernel stack overflow at {:#X}, accessed {:#X}", registers.rip, address.value());
+        }
+    }
+
+    // Todo: Deliver the exception to the process once there are user processes, until then it stops the kernel as well
+    if is_user {
+        panic!("{} in user mode at {:#X}", name, registers.rip);
+    }
+
+    panic!("{} at {:#X}", name, registers.rip);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_architectural_exceptions_have_error_codes() {
+        let error_code_numbers: std::vec::Vec<u64> = (0..32).filter(|number| has_error_code(*number)).collect();
+        assert_eq!(error_code_numbers, [8, 10, 11, 12, 13, 14, 17, 21, 29, 30]);
+    }
+
+    #[test]
+    fn reserved_vectors_are_not_exceptions() {
+        for number in [15, 22, 27, 31] {
+            assert_eq!(Exception::from_interrupt_number(number), None);
+        }
+
+        assert_eq!(Exception::from_interrupt_number(13), Some(Exception::GeneralProtection));
+    }
+}
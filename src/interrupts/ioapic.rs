@@ -11,6 +11,9 @@ const IOREDTBL: usize = 0x10;
 
 const DISABLE_FLAG: u32 = 1 << 16;
 
+// Todo: Read the real count from the version register, 24 is what most IOAPICs have
+pub const REDIRECTION_ENTRY_COUNT: u8 = 24;
+
 pub struct IOAPIC {
     registers: *mut u32
 }
@@ -1,7 +1,23 @@
-use crate::{debug_write_line, memory::{mapper, GiB, KERNEL_CODE_SELECTOR, SMALL_PAGE_SIZE}};
+use crate::{
+    debug_write_line,
+    low::{gdt, x64::kernel_paging_table},
+    memory::{
+        mapper,
+        page_fault,
+        paging_table::PagingFlags,
+        tlb,
+        GiB,
+        PhysicalAddress,
+        VirtualAddress,
+        KERNEL_CODE_SELECTOR,
+        SMALL_PAGE_SIZE
+    }
+};
 use core::{mem, ptr, slice};
 
 pub mod apic;
+pub mod dispatch;
+pub mod exceptions;
 pub mod ioapic;
 
 extern "C" {
@@ -17,6 +33,7 @@ extern "C" {
 const INTERRUPT_BASE: u8 = 0x20;
 const MAX_INTERRUPT_COUNT: usize = 256;
 const EXCEPTION_COUNT: usize = 32;
+const DOUBLE_FAULT_INTERRUPT_NUMBER: u64 = 8;
 const IDT_SIZE: usize = MAX_INTERRUPT_COUNT * mem::size_of::<IDT>();
 
 const PRESENT_BIT: u8 = 1 << 7;
@@ -26,6 +43,38 @@ enum GateKind {
     Trap = 0xf
 }
 
+// Registers saved by `interrupts_entry`, in the order they are on the stack
+#[repr(C)]
+pub struct RegisterState {
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub rbx: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rax: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+
+    // Pushed by the interrupt stub
+    pub interrupt_number: u64,
+    pub error_code: u64, // Padding for the interrupts that do not have an error code
+
+    // Pushed by the processor
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub interrupted_rsp: u64,
+    pub ss: u64
+}
+
 #[repr(packed)]
 struct IDTR {
     size: u16,
@@ -76,7 +125,8 @@ unsafe fn initialize_unsafe(
     let idt = slice::from_raw_parts_mut(idt_address as *mut IDT, MAX_INTERRUPT_COUNT);
     idt.fill(IDT::empty());
 
-    let interrupt_handler = mapper::to_kernel_address(interrupts_entry as usize) as u64;
+    // Note: The direct map is not executable, so the handler and the stubs run from the identity map like the rest of the kernel
+    let interrupt_handler = interrupts_entry as *const () as u64;
     let mut interrupt_stub = interrupt_stubs_address as *mut u8;
 
     debug_write_line!("Interrupts: Interrupt handler: {:#X}", interrupt_handler);
@@ -88,20 +138,57 @@ unsafe fn initialize_unsafe(
             GateKind::Trap
         };
 
-        configure_interrupt(idt, interrupt_number, gate, 0, interrupt_stub as u64);
+        let stack_index = if interrupt_number == DOUBLE_FAULT_INTERRUPT_NUMBER as usize {
+            gdt::DOUBLE_FAULT_STACK_INDEX
+        } else {
+            gdt::INTERRUPT_STACK_INDEX
+        };
+
+        configure_interrupt(idt, interrupt_number, gate, 0, stack_index, interrupt_stub as u64);
 
         interrupt_stub = write_interrupt_stub(interrupt_stub, interrupt_handler, interrupt_number as u32);
     }
 
+    // The stubs were written through a writable mapping, so now they can be made executable
+    protect_interrupt_stubs(interrupt_stubs_address);
+
+    register_kernel_handlers();
+
     debug_write_line!("Interrupts: Setting IDTR to {:#X}", idtr_address);
     interrupts_set_idtr(idtr_address);
 }
 
+// Maps the stubs as read-only and executable, so that they are never writable and executable at the same time
+fn protect_interrupt_stubs(interrupt_stubs_address: u64) {
+    let mut paging_table = kernel_paging_table();
+    let stubs = VirtualAddress::new(interrupt_stubs_address as usize);
+
+    paging_table.protect(stubs, SMALL_PAGE_SIZE, PagingFlags::ReadOnly).unwrap();
+    paging_table.protect(
+        VirtualAddress::to_kernel(PhysicalAddress::new(stubs.value())),
+        SMALL_PAGE_SIZE,
+        PagingFlags::ReadOnly | PagingFlags::NoExecute
+    ).unwrap();
+}
+
+fn register_kernel_handlers() {
+    let register = |interrupt_number: u64, handler: dispatch::InterruptHandler| {
+        dispatch::register_handler(interrupt_number as u8, handler, false).expect("Interrupts: Failed to register a kernel handler");
+    };
+
+    register(page_fault::PAGE_FAULT_INTERRUPT_NUMBER, |registers| page_fault::handle(registers.error_code, registers.rip));
+    register(tlb::TLB_SHOOTDOWN_INTERRUPT_NUMBER, |_| {
+        tlb::handle_shootdown();
+        true
+    });
+}
+
 pub fn initialize() {
     unsafe {
         let idtr_address = mapper::to_kernel(interrupts_tables.as_ptr()) as u64;
         let idt_address = idtr_address + (SMALL_PAGE_SIZE as u64);
-        let interrupt_stubs_address = idt_address + (SMALL_PAGE_SIZE as u64);
+        // Note: The kernel is identity mapped, so the stubs are at the same physical address
+        let interrupt_stubs_address = ptr::addr_of!(interrupts_tables) as u64 + 2 * (SMALL_PAGE_SIZE as u64);
         initialize_unsafe(idtr_address, idt_address, interrupt_stubs_address);
     }
 }
@@ -111,12 +198,13 @@ fn configure_interrupt(
     index: usize,
     gate: GateKind,
     privilege: u8,
+    stack_index: u8,
     handler: u64
 ) {
     idt[index] = IDT {
         offset_1: handler as u16,
         selector: KERNEL_CODE_SELECTOR,
-        interrupt_stack_table_offset: 1,
+        interrupt_stack_table_offset: stack_index,
         type_attributes: (gate as u8) | PRESENT_BIT | ((privilege & 0b11) << 5),
         offset_2: (handler >> 16) as u16,
         offset_3: (handler >> 32) as u32,
@@ -129,7 +217,8 @@ pub unsafe fn write_interrupt_stub(
     interrupt_handler: u64,
     interrupt_number: u32
 ) -> *mut u8 {
-    if interrupt_number < EXCEPTION_COUNT as u32 {
+    // Note: The processor pushes an error code only for some exceptions, the other stubs push the interrupt number as padding
+    if exceptions::has_error_code(interrupt_number as u64) {
         // push qword <interrupt>
         *interrupt_stub = 0x68;
         interrupt_stub = interrupt_stub.add(1);
@@ -183,7 +272,28 @@ pub fn disable() {
 }
 
 #[no_mangle]
-pub fn interrupts_kernel_entry() {
-    debug_write_line!("Hello Interrupt :^)");
-    loop {}
+pub extern "C" fn interrupts_kernel_entry(registers: &mut RegisterState) {
+    let interrupt_number = registers.interrupt_number;
+
+    if interrupt_number == apic::SPURIOUS_INTERRUPT_NUMBER {
+        return;
+    }
+
+    let handled = dispatch::dispatch(registers);
+
+    // Exceptions can't be ignored, because returning would run the faulting instruction again
+    if interrupt_number < EXCEPTION_COUNT as u64 {
+        if !handled {
+            exceptions::fail(registers);
+        }
+
+        return;
+    }
+
+    if !handled {
+        debug_write_line!("Interrupts: Unhandled interrupt {:#X}", interrupt_number);
+    }
+
+    // Note: The handlers don't acknowledge the interrupt themselves, because a shared vector is acknowledged once
+    apic::end_of_interrupt();
 }
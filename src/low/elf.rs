@@ -0,0 +1,63 @@
+use bitflags::bitflags;
+use core::{mem, ptr, slice};
+
+// Minimal ELF64 definitions for inspecting the kernel image
+pub const PROGRAM_HEADER_KIND_LOAD: u32 = 1;
+
+bitflags! {
+    #[derive(Clone, Copy)]
+    pub struct SegmentFlags: u32 {
+        const Executable = 1 << 0;
+        const Writable = 1 << 1;
+        const Readable = 1 << 2;
+    }
+}
+
+#[repr(C)]
+pub struct FileHeader {
+    pub identification: [u8; 16],
+    pub kind: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub program_header_offset: u64,
+    pub section_header_offset: u64,
+    pub flags: u32,
+    pub header_size: u16,
+    pub program_header_entry_size: u16,
+    pub program_header_count: u16,
+    pub section_header_entry_size: u16,
+    pub section_header_count: u16,
+    pub section_names_index: u16
+}
+
+#[repr(C)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub virtual_address: u64,
+    pub physical_address: u64,
+    pub file_size: u64,
+    pub memory_size: u64,
+    pub alignment: u64
+}
+
+extern "C" {
+    // Defined by the linker at the start of the first segment, which contains the headers
+    static __ehdr_start: FileHeader;
+}
+
+pub fn kernel_program_headers() -> &'static [ProgramHeader] {
+    unsafe {
+        let header = &*ptr::addr_of!(__ehdr_start);
+
+        assert!(
+            header.program_header_entry_size as usize == mem::size_of::<ProgramHeader>(),
+            "ELF: Unexpected program header size"
+        );
+
+        let program_headers = (header as *const FileHeader as *const u8).add(header.program_header_offset as usize);
+        slice::from_raw_parts(program_headers as *const ProgramHeader, header.program_header_count as usize)
+    }
+}
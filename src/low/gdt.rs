@@ -0,0 +1,106 @@
+use alloc::boxed::Box;
+use core::{mem, ptr};
+
+use crate::memory::{VirtualAddress, KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR};
+
+extern "C" {
+    fn write_gdtr(gdtr: u64);
+    fn reload_segments(code_selector: u64, data_selector: u64);
+    fn write_task_register(selector: u64);
+}
+
+pub const TASK_STATE_SEGMENT_SELECTOR: u16 = 0x28;
+
+// Indices to the interrupt stack table, zero means that the interrupt uses the current stack
+pub const INTERRUPT_STACK_INDEX: u8 = 1;
+pub const DOUBLE_FAULT_STACK_INDEX: u8 = 2; // Double faults get their own stack, so that they can be handled even if the other stacks are broken
+
+// Note: The user data segment must be right before the user code segment, because sysret expects them in that order
+const KERNEL_CODE_DESCRIPTOR: u64 = 0x00af9a000000ffff;
+const KERNEL_DATA_DESCRIPTOR: u64 = 0x00cf92000000ffff;
+const USER_DATA_DESCRIPTOR: u64 = 0x00cff2000000ffff;
+const USER_CODE_DESCRIPTOR: u64 = 0x00affa000000ffff;
+
+const AVAILABLE_TASK_STATE_SEGMENT: u64 = 0x89;
+
+const DESCRIPTOR_COUNT: usize = 7; // Null, kernel code, kernel data, user data, user code and the task state segment (2 entries)
+
+#[repr(C, packed)]
+struct TaskStateSegment {
+    reserved_1: u32,
+    privilege_stacks: [u64; 3], // Stacks that are loaded when the privilege level changes
+    reserved_2: u64,
+    interrupt_stacks: [u64; 7],
+    reserved_3: u64,
+    reserved_4: u16,
+    io_map_base: u16
+}
+
+#[repr(C, packed)]
+struct Gdtr {
+    size: u16,
+    table: u64
+}
+
+// Descriptor tables of a processor
+#[repr(C, align(16))]
+pub struct DescriptorTables {
+    descriptors: [u64; DESCRIPTOR_COUNT],
+    task_state_segment: TaskStateSegment,
+    gdtr: Gdtr
+}
+
+impl DescriptorTables {
+    // Creates the tables, the stacks are the tops of the kernel stack and the interrupt stacks
+    pub fn create(kernel_stack: VirtualAddress, interrupt_stack: VirtualAddress, double_fault_stack: VirtualAddress) -> &'static DescriptorTables {
+        let mut interrupt_stacks = [0; 7];
+        interrupt_stacks[INTERRUPT_STACK_INDEX as usize - 1] = interrupt_stack.value() as u64;
+        interrupt_stacks[DOUBLE_FAULT_STACK_INDEX as usize - 1] = double_fault_stack.value() as u64;
+
+        let tables = Box::leak(Box::new(Self {
+            descriptors: [0, KERNEL_CODE_DESCRIPTOR, KERNEL_DATA_DESCRIPTOR, USER_DATA_DESCRIPTOR, USER_CODE_DESCRIPTOR, 0, 0],
+            task_state_segment: TaskStateSegment {
+                reserved_1: 0,
+                privilege_stacks: [kernel_stack.value() as u64, 0, 0],
+                reserved_2: 0,
+                interrupt_stacks,
+                reserved_3: 0,
+                reserved_4: 0,
+                io_map_base: mem::size_of::<TaskStateSegment>() as u16 // No I/O permission map
+            },
+            gdtr: Gdtr { size: 0, table: 0 }
+        }));
+
+        // The task state segment descriptor is twice the size of the other descriptors, because it contains a 64-bit address
+        let base = ptr::addr_of!(tables.task_state_segment) as u64;
+        let limit = (mem::size_of::<TaskStateSegment>() - 1) as u64;
+        let index = (TASK_STATE_SEGMENT_SELECTOR / 8) as usize;
+
+        tables.descriptors[index] = (limit & 0xffff) |
+            ((base & 0xffffff) << 16) |
+            (AVAILABLE_TASK_STATE_SEGMENT << 40) |
+            (((limit >> 16) & 0xf) << 48) |
+            (((base >> 24) & 0xff) << 56);
+        tables.descriptors[index + 1] = base >> 32;
+
+        tables.gdtr = Gdtr {
+            size: (mem::size_of::<[u64; DESCRIPTOR_COUNT]>() - 1) as u16,
+            table: tables.descriptors.as_ptr() as u64
+        };
+
+        tables
+    }
+
+    pub fn gdtr_address(&self) -> VirtualAddress {
+        VirtualAddress::new(ptr::addr_of!(self.gdtr) as usize)
+    }
+
+    // Loads the tables on the current processor
+    pub fn load(&self) {
+        unsafe {
+            write_gdtr(self.gdtr_address().value() as u64);
+            reload_segments(KERNEL_CODE_SELECTOR as u64, KERNEL_DATA_SELECTOR as u64);
+            write_task_register(TASK_STATE_SEGMENT_SELECTOR as u64);
+        }
+    }
+}
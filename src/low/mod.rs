@@ -1,3 +1,5 @@
+pub mod elf;
+pub mod gdt;
 pub mod ports;
 pub mod processor;
 pub mod x64;
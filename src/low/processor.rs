@@ -1,5 +1,9 @@
-use crate::{memory::VirtualAddress, low::x64::{MSR_GS_BASE, read_msr, write_msr}};
+use crate::{memory::{magazine::AllocationCaches, numa, tlb, VirtualAddress}, low::x64::{MSR_GS_BASE, read_msr, write_msr}};
 use alloc::boxed::Box;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// Number of processors that have been started
+static ONLINE_COUNT: AtomicUsize = AtomicUsize::new(0);
 
 #[repr(packed)]
 pub struct Processor {
@@ -8,18 +12,35 @@ pub struct Processor {
     pub user_stack_pointer: VirtualAddress,
     pub general_kernel_stack_pointer: VirtualAddress,
     pub gdtr_physical_address: VirtualAddress,
-    pub index: u32
+    pub index: u32,
+    pub allocation_caches: *mut AllocationCaches,
+    pub node: u32, // The NUMA node, whose memory is the closest to the processor
+    pub shootdown_generation: usize // The last TLB shootdown request the processor has handled or sent
 }
 
 impl Processor {
-    pub fn create(kernel_stack_pointer: VirtualAddress, gdtr_physical_address: VirtualAddress, index: u32) -> &'static Processor {
+    // Note: The interrupt stack must be the stack the processor switches to on interrupts, because the interrupt entry copies
+    // the interrupt frame back to it before returning
+    pub fn create(
+        kernel_stack_pointer: VirtualAddress,
+        interrupt_stack_pointer: VirtualAddress,
+        gdtr_physical_address: VirtualAddress,
+        index: u32
+    ) -> &'static Processor {
+        // Note: The caches are allocated before GS points to the processor, so the allocation itself does not use them
+        let allocation_caches = Box::leak(Box::new(AllocationCaches::new()));
+
         let processor = Box::new(Self {
             padding: 0,
             kernel_stack_pointer,
             user_stack_pointer: VirtualAddress::null(),
-            general_kernel_stack_pointer: kernel_stack_pointer,
+            general_kernel_stack_pointer: interrupt_stack_pointer,
             gdtr_physical_address,
-            index
+            index,
+            allocation_caches,
+            node: numa::node_of_processor(numa::current_apic_id()) as u32,
+            // Note: The processor is not counted by the shootdown request in flight, if there is one
+            shootdown_generation: tlb::shootdown_generation()
         });
 
         // Write the processor's address to the GS register, so that the interrupt handler can access the fields
@@ -27,9 +48,24 @@ impl Processor {
             write_msr(MSR_GS_BASE, Box::as_ptr(&processor) as u64);
         }
 
+        ONLINE_COUNT.fetch_add(1, Ordering::AcqRel);
+
         Box::leak(processor)
     }
 
+    // Returns None if the processor has not been created yet
+    pub fn try_current() -> Option<&'static mut Processor> {
+        unsafe {
+            let address = read_msr(MSR_GS_BASE);
+
+            if address == 0 {
+                return None;
+            }
+
+            Some(&mut *(address as *mut Processor))
+        }
+    }
+
     pub fn current() -> &'static mut Processor {
         unsafe {
             let address = read_msr(MSR_GS_BASE);
@@ -37,3 +73,7 @@ impl Processor {
         }
     }
 }
+
+pub fn online_count() -> usize {
+    ONLINE_COUNT.load(Ordering::Acquire)
+}
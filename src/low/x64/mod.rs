@@ -3,10 +3,37 @@ use crate::memory::{PhysicalAddress, paging_table::PagingTable};
 pub mod serial;
 
 pub const MSR_GS_BASE: usize = 0xc0000101;
+pub const MSR_EFER: usize = 0xc0000080;
+pub const MSR_PAT: usize = 0x277;
+
+pub const EFER_NO_EXECUTE_ENABLE: u64 = 1 << 11;
+pub const CR0_WRITE_PROTECT: u64 = 1 << 16;
+pub const CR4_FIVE_LEVEL_PAGING: u64 = 1 << 12;
+
+pub const CPUID_EXTENDED_FEATURES_LEAF: u32 = 7;
+pub const CPUID_EXTENDED_FEATURES_FIVE_LEVEL_PAGING: u32 = 1 << 16; // ECX
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32
+}
 
 extern "C" {
     pub fn write_cr3(value: u64) -> u64;
     pub fn read_cr3() -> u64;
+    pub fn read_cr2() -> u64;
+    pub fn write_cr0(value: u64);
+    pub fn read_cr0() -> u64;
+    pub fn read_cr4() -> u64;
+    pub fn registers_rbp() -> u64;
+
+    fn read_cpuid(leaf: u32, subleaf: u32, result: *mut CpuidResult);
+    pub fn enable_five_level_paging(top_level_table: u64);
+    pub fn call_on_stack(function: u64, stack_top: u64) -> !;
 
     // Note: MSR = Model Specific Register
     pub fn write_msr(id: usize, value: u64);
@@ -17,3 +44,22 @@ pub fn kernel_paging_table() -> PagingTable<'static> {
     let entries_physical_address = unsafe { PhysicalAddress::new(read_cr3() as usize) };
     PagingTable::from_physical_address(entries_physical_address)
 }
+
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    let mut result = CpuidResult::default();
+    unsafe { read_cpuid(leaf, subleaf, &mut result) };
+    result
+}
+
+pub fn is_five_level_paging_supported() -> bool {
+    // The leaf is only valid if the processor reports it as supported
+    if cpuid(0, 0).eax < CPUID_EXTENDED_FEATURES_LEAF {
+        return false;
+    }
+
+    (cpuid(CPUID_EXTENDED_FEATURES_LEAF, 0).ecx & CPUID_EXTENDED_FEATURES_FIVE_LEVEL_PAGING) != 0
+}
+
+pub fn is_five_level_paging_enabled() -> bool {
+    unsafe { (read_cr4() & CR4_FIVE_LEVEL_PAGING) != 0 }
+}
@@ -1,10 +1,12 @@
 #![feature(box_as_ptr)]
 
-#![no_std]
-#![no_main]
+// Note: Tests run on the host, so they need the standard library and the test harness entry point
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 extern crate alloc;
 
-use core::panic::PanicInfo;
+#[cfg(not(test))]
+use core::{mem, panic::PanicInfo};
 
 #[derive(Clone, Copy, PartialEq)]
 pub enum RegionKind {
@@ -70,19 +72,41 @@ pub mod interrupts;
 pub mod low;
 pub mod memory;
 
-use low::{x64::serial, processor::Processor};
-use memory::{mapper, physical_buddy_allocator, PhysicalAddress, VirtualAddress};
-
-unsafe fn clear_screen(info: &BootInfo) {
+#[cfg(not(test))]
+use low::{gdt::DescriptorTables, x64::{call_on_stack, serial}, processor::Processor};
+#[cfg(not(test))]
+use memory::{
+    frame_database,
+    ioremap::{ioremap, IoMapping},
+    kernel_stack::{self, KernelStack},
+    magazine,
+    mapper,
+    paging_table::CacheType,
+    physical_buddy_allocator::{self, KernelMemory, PhysicalBuddyAllocator},
+    physical_slab_allocator,
+    reclaim,
+    PhysicalAddress
+};
+
+#[cfg(not(test))]
+fn clear_screen(info: &BootInfo, framebuffer: &IoMapping) {
     for y in 0..info.graphics.height {
         for x in 0..info.graphics.width {
             let offset = (y * info.graphics.stride + x * 4) as usize;
-            let pixel = (info.graphics.framebuffer + offset) as *mut u32;
-            *pixel = 0xff0000ff;
+            framebuffer.write::<u32>(offset, 0xff0000ff);
         }
     }
 }
 
+#[cfg(not(test))]
+// Note: The pixels are only written, so write-combining lets the processor send them in bursts
+fn map_framebuffer(info: &BootInfo) -> IoMapping {
+    let size = (info.graphics.stride * info.graphics.height) as usize;
+    ioremap(PhysicalAddress::new(info.graphics.framebuffer), size, CacheType::WriteCombining)
+        .expect("Boot: Failed to map the framebuffer")
+}
+
+#[cfg(not(test))]
 unsafe fn print_region_info(info: &BootInfo) {
     for index in 0..info.regions.length {
         let region = *info.regions.data.add(index);
@@ -95,6 +119,7 @@ unsafe fn print_region_info(info: &BootInfo) {
     }
 }
 
+#[cfg(not(test))]
 unsafe fn allocate_physical_memory_manager(info: &BootInfo) -> PhysicalAddress {
     // Find the first available region capable of containing the physical memory allocator
     let regions = &info.regions;
@@ -103,11 +128,16 @@ unsafe fn allocate_physical_memory_manager(info: &BootInfo) -> PhysicalAddress {
     let kernel_end: PhysicalAddress = info.kernel_regions.find_end(|_| true).into();
     debug_write_line!("Boot: Kernel ends at {:#X}", kernel_end.value());
 
+    // The size of the allocator depends on how much physical memory there is
+    let max_available_physical_address: PhysicalAddress =
+        regions.find_end(|region| region.kind == RegionKind::Available).into();
+    let allocation_size = PhysicalBuddyAllocator::<KernelMemory>::get_allocation_size(max_available_physical_address);
+
     for index in 0..regions.length {
         let region = regions.data.add(index).as_ref().expect("Failed to access memory region");
 
         if region.kind == RegionKind::Available &&
-            region.size() >= physical_buddy_allocator::ALLOCATION_SIZE &&
+            region.size() >= allocation_size &&
             region.end >= kernel_end.value() {
             debug_write_line!("Boot: Placing physical buddy allocator at {:#X}", region.start);
 
@@ -122,30 +152,65 @@ unsafe fn allocate_physical_memory_manager(info: &BootInfo) -> PhysicalAddress {
     panic!("Failed to allocate the physical memory manager");
 }
 
-// Todo: We need larger stack
-#[repr(align(16))]
-struct KernelStack([u8; 0x2000]);
-static KERNEL_STACK: KernelStack = KernelStack([0; 0x2000]);
-
+#[cfg(not(test))]
 #[no_mangle]
 pub unsafe extern "C" fn _start(info_pointer: *const BootInfo) -> ! {
     debug_write_line!("Boot: Entered the kernel :^)");
 
     let info = &*info_pointer;
-    clear_screen(&info);
     print_region_info(&info);
     let max_available_physical_address = allocate_physical_memory_manager(&info);
 
+    physical_buddy_allocator::instance.lock().statistics().print();
+    // Note: The caches of the processor are drained first, so that the slabs they emptied can be released
+    reclaim::register(magazine::reclaim);
+    reclaim::register(physical_slab_allocator::reclaim);
+
     // We can't rely on the paging table provided by UEFI, because
     // the table might use gigantic pages (1 GiB)
-    mapper::switch_to_kernel_paging_table(max_available_physical_address);
+    mapper::switch_to_kernel_paging_table(max_available_physical_address, &info.kernel_regions);
+
+    frame_database::initialize(&info.regions, &info.kernel_regions);
+    frame_database::instance.lock().print_ownership(&info.regions);
+
+    // The framebuffer lives as long as the kernel, because this function never returns
+    let framebuffer = map_framebuffer(&info);
+    clear_screen(&info, &framebuffer);
 
     interrupts::initialize();
     interrupts::apic::initialize(PhysicalAddress::new(info.rsdp_physical_address as usize));
 
-    // Todo: Allocate the stack?
-    let kernel_stack = KERNEL_STACK.0.as_ptr() as usize;
-    let _ = Processor::create(VirtualAddress::new(kernel_stack), VirtualAddress::null(), 0);
+    // Note: The node of the processor is looked up when the processor is created
+    memory::numa::initialize(PhysicalAddress::new(info.rsdp_physical_address as usize));
+
+    let allocate_stack = || KernelStack::allocate(kernel_stack::DEFAULT_STACK_SIZE).expect("Boot: Failed to allocate a stack");
+    let kernel_stack = allocate_stack();
+    let interrupt_stack = allocate_stack();
+    let double_fault_stack = allocate_stack();
+
+    let descriptor_tables = DescriptorTables::create(kernel_stack.top(), interrupt_stack.top(), double_fault_stack.top());
+    descriptor_tables.load();
+
+    let _ = Processor::create(kernel_stack.top(), interrupt_stack.top(), descriptor_tables.gdtr_address(), 0);
+
+    // The stack the bootloader gave us has no guard page, so the kernel continues on a stack of its own.
+    // Note: The kernel stack of the processor can not be used, because the interrupt entry saves the registers at its top.
+    let boot_stack = allocate_stack();
+    let boot_stack_top = boot_stack.top();
+
+    // The processor uses the stacks until shutdown
+    mem::forget(kernel_stack);
+    mem::forget(interrupt_stack);
+    mem::forget(double_fault_stack);
+    mem::forget(boot_stack);
+
+    call_on_stack(finish_boot as *const () as u64, boot_stack_top.value() as u64);
+}
+
+#[cfg(not(test))]
+extern "C" fn finish_boot() -> ! {
+    #[cfg(feature = "heap-debug")]
+    memory::kernel_allocator::report();
 
     debug_write_line!("Done.");
 
@@ -153,6 +218,7 @@ pub unsafe extern "C" fn _start(info_pointer: *const BootInfo) -> ! {
     loop {}
 }
 
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     if let Some(location) = info.location() {
@@ -0,0 +1,407 @@
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{alloc::Layout, ops::Range, ptr};
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::{debug_write_line, low::x64::{read_cr3, write_cr3}};
+
+use super::{
+    frame_database::{self, FrameOwner},
+    mapper::{self, KERNEL_ENTRY_INDEX},
+    paging_table::{self, MappingError, PagingFlags, PagingTable, PAGING_TABLE_ENTRY_COUNT},
+    physical_buddy_allocator,
+    tlb::{self, FlushSet},
+    PhysicalAddress, VirtualAddress, SMALL_PAGE_SIZE
+};
+
+// The top-level entries from the kernel entry onwards map the kernel and are shared by all the address spaces
+pub const KERNEL_ENTRY_INDICES: Range<usize> = KERNEL_ENTRY_INDEX..PAGING_TABLE_ENTRY_COUNT;
+
+const PRIVATE_ENTRY_INDICES: Range<usize> = 0..KERNEL_ENTRY_INDEX;
+
+// The kernel still runs from the identity map, so the L3 entries that map the kernel image are shared.
+// Note: The tables above them are private, so the private half starts at the first gigabyte after the kernel image.
+const IDENTITY_ENTRY_LEVEL: usize = 3;
+
+fn identity_map_end() -> usize {
+    mapper::kernel_image_end().next_multiple_of(PagingTable::get_entry_size(IDENTITY_ENTRY_LEVEL))
+}
+
+fn allocate_zeroed_frame() -> PhysicalAddress {
+    try_allocate_zeroed_frame().expect("Address space: Out of memory")
+}
+
+fn try_allocate_frame() -> Option<PhysicalAddress> {
+    let layout = Layout::from_size_align(SMALL_PAGE_SIZE, SMALL_PAGE_SIZE).unwrap();
+    let frame = PhysicalAddress::from(VirtualAddress::new(physical_buddy_allocator::try_allocate(layout).ok()? as usize));
+
+    frame_database::set_owner(frame, SMALL_PAGE_SIZE, FrameOwner::User);
+    Some(frame)
+}
+
+fn try_allocate_zeroed_frame() -> Option<PhysicalAddress> {
+    let frame = try_allocate_frame()?;
+
+    unsafe {
+        ptr::write_bytes(VirtualAddress::to_kernel(frame).value() as *mut u8, 0, SMALL_PAGE_SIZE);
+    }
+
+    Some(frame)
+}
+
+fn free_frame(physical_address: PhysicalAddress) {
+    let layout = Layout::from_size_align(SMALL_PAGE_SIZE, SMALL_PAGE_SIZE).unwrap();
+    let frame = VirtualAddress::to_kernel(physical_address).value() as *mut u8;
+    physical_buddy_allocator::deallocate(frame, layout);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AreaError {
+    WritableAndExecutable
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum AreaKind {
+    Zero, // Backed by zeroed frames that are allocated when the pages are touched for the first time
+    Physical(PhysicalAddress) // Backed by existing physical memory that is mapped when the pages are touched for the first time
+}
+
+#[derive(Clone, Copy)]
+pub struct VirtualMemoryArea {
+    pub start: VirtualAddress,
+    pub end: VirtualAddress,
+    pub flags: PagingFlags,
+    pub kind: AreaKind
+}
+
+impl VirtualMemoryArea {
+    pub fn contains(&self, address: VirtualAddress) -> bool {
+        self.start <= address && address < self.end
+    }
+
+    // Maps the page that contains the address, returns false if there is no memory for it
+    pub fn populate(&self, paging_table: &mut PagingTable, address: VirtualAddress) -> bool {
+        let page = address.align(SMALL_PAGE_SIZE);
+
+        match self.kind {
+            AreaKind::Zero => {
+                let Some(frame) = try_allocate_zeroed_frame() else {
+                    return false;
+                };
+
+                paging_table
+                    .map_page(page, frame, self.flags | PagingFlags::Owned)
+                    .expect("Address space: Area was writable and executable");
+            },
+            AreaKind::Physical(base) => {
+                let physical_address = PhysicalAddress::new(base.value() + (page.value() - self.start.value()));
+                paging_table
+                    .map_page(page, physical_address, self.flags)
+                    .expect("Address space: Area was writable and executable");
+            }
+        }
+
+        true
+    }
+}
+
+lazy_static! {
+    // Virtual memory areas of each address space by the physical address of its top-level table, so that
+    // the page fault handler can find them through CR3
+    static ref areas: Mutex<BTreeMap<PhysicalAddress, Vec<VirtualMemoryArea>>> = {
+        Mutex::new(BTreeMap::new())
+    };
+}
+
+fn share_frame(frame: PhysicalAddress) {
+    frame_database::reference(frame);
+}
+
+// Drops a reference to the owned frame and frees the frame if it was the last reference
+fn release_frame(frame: PhysicalAddress) {
+    if frame_database::release(frame) == 0 {
+        free_frame(frame);
+    }
+}
+
+// Gives the address space its own copy of the shared page that contains the address.
+// Returns false if the page is not copy-on-write or there is no memory for the copy.
+pub fn copy_on_write(paging_table: &mut PagingTable, address: VirtualAddress) -> bool {
+    let page = address.align(SMALL_PAGE_SIZE);
+
+    let Some((frame, flags)) = paging_table.translate(page) else {
+        return false;
+    };
+
+    if !flags.contains(PagingFlags::CopyOnWrite) {
+        return false;
+    }
+
+    let writable_flags = flags.difference(PagingFlags::ReadOnly | PagingFlags::CopyOnWrite);
+    let reference_count = frame_database::get(frame).expect("Address space: Shared frame has no metadata").reference_count;
+
+    // The other address spaces have dropped the frame already, so it can be written to directly
+    if reference_count == 1 {
+        paging_table
+            .protect(page, SMALL_PAGE_SIZE, writable_flags)
+            .expect("Address space: Copy-on-write page was executable");
+        return true;
+    }
+
+    let Some(copy) = try_allocate_frame() else {
+        return false;
+    };
+
+    unsafe {
+        ptr::copy_nonoverlapping(
+            VirtualAddress::to_kernel(frame).value() as *const u8,
+            VirtualAddress::to_kernel(copy).value() as *mut u8,
+            SMALL_PAGE_SIZE
+        );
+    }
+
+    release_frame(frame);
+    paging_table.map_page(page, copy, writable_flags).expect("Address space: Copy-on-write page was executable");
+    true
+}
+
+// Returns the area that contains the address in the address space of the top-level table
+pub fn find_area(top_level_physical_address: PhysicalAddress, address: VirtualAddress) -> Option<VirtualMemoryArea> {
+    let all_areas = areas.lock();
+    let address_space_areas = all_areas.get(&top_level_physical_address)?;
+
+    address_space_areas.iter().find(|area| area.contains(address)).copied()
+}
+
+// The lower half of the virtual memory is private to each address space, except for the kernel image, while the kernel half is shared.
+// Note: The address space owns its private tables and the frames mapped with `PagingFlags::Owned`, which it may share with its clones.
+pub struct AddressSpace {
+    entries: &'static mut [u64]
+}
+
+impl AddressSpace {
+    pub fn new() -> AddressSpace {
+        let entries = paging_table::allocate_table_entries();
+
+        // Every address space shares the kernel half with the current one
+        let current = Self::current_paging_table();
+        let mut paging_table = PagingTable::new(entries);
+        paging_table.share_entries(&current, KERNEL_ENTRY_INDICES);
+        paging_table.share_lower_entries(&current, IDENTITY_ENTRY_LEVEL, identity_map_end());
+
+        debug_write_line!("Address space: Created an address space at {:p}", entries.as_ptr());
+
+        let address_space = Self { entries };
+        areas.lock().insert(address_space.physical_address(), Vec::new());
+
+        address_space
+    }
+
+    fn current_paging_table() -> PagingTable<'static> {
+        PagingTable::from_physical_address(PhysicalAddress::new(unsafe { read_cr3() } as usize))
+    }
+
+    pub fn physical_address(&self) -> PhysicalAddress {
+        PhysicalAddress::from(VirtualAddress::new(self.entries.as_ptr() as usize))
+    }
+
+    pub fn is_active(&self) -> bool {
+        unsafe { read_cr3() as usize == self.physical_address().value() }
+    }
+
+    pub fn is_private(virtual_address: VirtualAddress) -> bool {
+        let top_level_entry_size = PagingTable::get_entry_size(paging_table::level_count());
+        let end = PRIVATE_ENTRY_INDICES.end * top_level_entry_size;
+
+        (identity_map_end()..end).contains(&virtual_address.value())
+    }
+
+    pub fn paging_table(&mut self) -> PagingTable<'_> {
+        PagingTable::new(self.entries)
+    }
+
+    // Maps zeroed frames that are owned by the address space
+    pub fn allocate(&mut self, virtual_address: VirtualAddress, size: usize, flags: PagingFlags) -> Result<(), MappingError> {
+        assert!(virtual_address.is_small_page_aligned(), "Address space: Virtual address was not small page aligned");
+        assert!(
+            Self::is_private(virtual_address) && Self::is_private(VirtualAddress::new(virtual_address.value() + size - 1)),
+            "Address space: Allocated memory must be in the private half"
+        );
+
+        // Note: Checked before any frame is allocated, so that a rejected allocation does not have to be undone
+        flags.validate()?;
+
+        for offset in (0..size).step_by(SMALL_PAGE_SIZE) {
+            let frame = allocate_zeroed_frame();
+
+            self.paging_table().map_page(
+                VirtualAddress::new(virtual_address.value() + offset),
+                frame,
+                flags | PagingFlags::Owned | PagingFlags::NoFlush
+            )?;
+        }
+
+        // Note: The address space may be active on other processors, even if it is not active on this one
+        tlb::flush_range(virtual_address, size);
+        Ok(())
+    }
+
+    // Reserves the range, the pages are populated by the page fault handler when they are touched
+    pub fn add_area(&mut self, area: VirtualMemoryArea) -> Result<(), AreaError> {
+        assert!(
+            area.start.is_small_page_aligned() && area.end.is_small_page_aligned() && area.start < area.end,
+            "Address space: Area must consist of whole pages"
+        );
+        assert!(
+            Self::is_private(area.start) && Self::is_private(VirtualAddress::new(area.end.value() - 1)),
+            "Address space: Area must be in the private half"
+        );
+
+        if !area.flags.intersects(PagingFlags::ReadOnly | PagingFlags::NoExecute) {
+            return Err(AreaError::WritableAndExecutable);
+        }
+
+        let mut all_areas = areas.lock();
+        let address_space_areas = all_areas.get_mut(&self.physical_address()).unwrap();
+
+        assert!(
+            address_space_areas.iter().all(|other| area.end <= other.start || other.end <= area.start),
+            "Address space: Area overlaps another area"
+        );
+
+        debug_write_line!(
+            "Address space: Adding {:?} area at {:#X}-{:#X}", area.kind, area.start.value(), area.end.value()
+        );
+
+        address_space_areas.push(area);
+        Ok(())
+    }
+
+    // Removes the area that starts at the address and the pages that were populated for it
+    pub fn remove_area(&mut self, start: VirtualAddress) {
+        let area = {
+            let mut all_areas = areas.lock();
+            let address_space_areas = all_areas.get_mut(&self.physical_address()).unwrap();
+            let index = address_space_areas
+                .iter()
+                .position(|area| area.start == start)
+                .expect("Address space: Removed area does not exist");
+
+            address_space_areas.remove(index)
+        };
+
+        let mut paging_table = self.paging_table();
+        let mut owned_frames = Vec::new();
+
+        for page in (area.start.value()..area.end.value()).step_by(SMALL_PAGE_SIZE) {
+            let page = VirtualAddress::new(page);
+            let owned = paging_table.translate(page).is_some_and(|(_, flags)| flags.contains(PagingFlags::Owned));
+
+            if let Some(physical_address) = paging_table.unmap_page(page, PagingFlags::NoFlush) {
+                if owned {
+                    owned_frames.push(physical_address);
+                }
+            }
+        }
+
+        // The frames can only be reused once no processor can write to them through stale translations
+        paging_table.flush_range(area.start, area.end.value() - area.start.value());
+
+        for frame in owned_frames {
+            release_frame(frame);
+        }
+    }
+
+    pub fn activate(&self) {
+        debug_write_line!("Address space: Activating the address space at {:p}", self.entries.as_ptr());
+
+        unsafe {
+            write_cr3(self.physical_address().value() as u64);
+        }
+    }
+}
+
+impl Default for AddressSpace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clone for AddressSpace {
+    // Shares the owned frames of the private half as copy-on-write, so that the copies do not affect each other
+    fn clone(&self) -> AddressSpace {
+        let mut address_space = AddressSpace::new();
+
+        let source_areas = areas.lock().get(&self.physical_address()).unwrap().clone();
+        areas.lock().insert(address_space.physical_address(), source_areas);
+
+        let mut paging_table = address_space.paging_table();
+        let source = PagingTable::from_physical_address(self.physical_address());
+        let mut source_writer = PagingTable::from_physical_address(self.physical_address());
+        let mut flush_set = FlushSet::new();
+
+        source.for_each_page(PRIVATE_ENTRY_INDICES, |virtual_address, physical_address, flags, level| {
+            let size = PagingTable::get_entry_size(level);
+
+            // Frames that are not owned, such as device memory, are shared instead of copied
+            if !flags.contains(PagingFlags::Owned) {
+                for offset in (0..size).step_by(SMALL_PAGE_SIZE) {
+                    paging_table.map_page(
+                        VirtualAddress::new(virtual_address.value() + offset),
+                        PhysicalAddress::new(physical_address.value() + offset),
+                        flags | PagingFlags::NoFlush
+                    ).expect("Address space: Shared page was writable and executable");
+                }
+
+                return;
+            }
+
+            assert!(level == 1, "Address space: Owned huge pages are not supported");
+
+            // Read-only frames can be shared as is, but writable frames are copied on the first write
+            let shared_flags = if flags.contains(PagingFlags::ReadOnly) && !flags.contains(PagingFlags::CopyOnWrite) {
+                flags
+            } else {
+                flags | PagingFlags::ReadOnly | PagingFlags::CopyOnWrite
+            };
+
+            share_frame(physical_address);
+            source_writer
+                .protect(virtual_address, SMALL_PAGE_SIZE, shared_flags | PagingFlags::NoFlush)
+                .expect("Address space: Shared page was writable and executable");
+            flush_set.add(virtual_address);
+            paging_table
+                .map_page(virtual_address, physical_address, shared_flags | PagingFlags::NoFlush)
+                .expect("Address space: Shared page was writable and executable");
+        });
+
+        // The source may have cached translations that still allow writing to the shared frames
+        flush_set.flush();
+
+        address_space
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "Address space: Can not drop the active address space");
+
+        debug_write_line!("Address space: Dropping the address space at {:p}", self.entries.as_ptr());
+
+        let mut paging_table = self.paging_table();
+
+        paging_table.for_each_page(PRIVATE_ENTRY_INDICES, |_, physical_address, flags, level| {
+            if flags.contains(PagingFlags::Owned) {
+                assert!(level == 1, "Address space: Owned huge pages are not supported");
+                release_frame(physical_address);
+            }
+        });
+
+        // Note: The shared entries are left alone, because the tables belong to the kernel
+        paging_table.free_tables(PRIVATE_ENTRY_INDICES);
+
+        areas.lock().remove(&self.physical_address());
+        paging_table::free_table_entries(self.entries);
+    }
+}
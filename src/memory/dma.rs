@@ -0,0 +1,89 @@
+use core::{alloc::Layout, ptr, slice};
+
+use crate::debug_write_line;
+
+use super::{
+    frame_database::{self, FrameFlags, FrameOwner},
+    physical_buddy_allocator::{self, Zone, L0_SIZE},
+    PhysicalAddress, VirtualAddress, SMALL_PAGE_SIZE
+};
+
+// Physically contiguous memory that a device can access directly.
+// Note: The frames are pinned while the buffer exists, because the device may access them at any time.
+pub struct DmaBuffer {
+    address: VirtualAddress,
+    bus_address: u64,
+    size: usize
+}
+
+impl DmaBuffer {
+    // Allocates a zeroed buffer in the zone or below it, returns None if there is not enough memory.
+    // Note: The buffer is a single buddy slab, so it can't be larger than an L0 slab.
+    pub fn allocate(size: usize, zone: Zone) -> Option<DmaBuffer> {
+        assert!(size != 0 && size <= L0_SIZE, "DMA buffer: Size {:#X} is not supported", size);
+
+        let size = size.next_multiple_of(SMALL_PAGE_SIZE);
+        let layout = Layout::from_size_align(size, SMALL_PAGE_SIZE).unwrap();
+
+        let Ok(pointer) = physical_buddy_allocator::try_allocate_in_zone(layout, zone) else {
+            debug_write_line!("DMA buffer: Out of memory in the {:?} zone", zone);
+            return None;
+        };
+
+        let address = VirtualAddress::new(pointer as usize);
+        let physical_address = PhysicalAddress::from(address);
+
+        // Note: The buddy allocator must not be locked here, because it locks the frame database itself
+        let mut frame_database = frame_database::instance.lock();
+        frame_database.set_owner(physical_address, size, FrameOwner::Dma);
+        frame_database.set_flags(physical_address, size, FrameFlags::Pinned, true);
+        drop(frame_database);
+
+        // The device may read the buffer before anything is written to it
+        unsafe {
+            ptr::write_bytes(pointer, 0, size);
+        }
+
+        debug_write_line!("DMA buffer: Allocated {} byte(s) at {:#X}", size, physical_address.value());
+
+        // Todo: Translate through the IOMMU once we program one, until then devices see physical addresses
+        Some(Self { address, bus_address: physical_address.value() as u64, size })
+    }
+
+    // The address the kernel accesses the buffer through.
+    // Note: The buffer is mapped write-back, which is safe because devices snoop the processor caches on x64.
+    pub fn address(&self) -> VirtualAddress {
+        self.address
+    }
+
+    // The address the device accesses the buffer through
+    pub fn bus_address(&self) -> u64 {
+        self.bus_address
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.address.value() as *const u8, self.size) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.address.value() as *mut u8, self.size) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        debug_write_line!("DMA buffer: Freeing {} byte(s) at {:#X}", self.size, self.bus_address);
+
+        // The frames must be unpinned first, because freeing a pinned frame is an error
+        let physical_address = PhysicalAddress::from(self.address);
+        frame_database::instance.lock().set_flags(physical_address, self.size, FrameFlags::Pinned, false);
+
+        // Note: The processor caches are bypassed, so that the memory goes back to its zone right away
+        let layout = Layout::from_size_align(self.size, SMALL_PAGE_SIZE).unwrap();
+        physical_buddy_allocator::instance.lock().deallocate(self.address.value() as *mut u8, layout);
+    }
+}
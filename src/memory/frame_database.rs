@@ -0,0 +1,207 @@
+use alloc::{boxed::Box, vec};
+
+use bitflags::bitflags;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::{debug_write_line, RegionKind, Regions};
+
+use super::{magazine, physical_buddy_allocator, PhysicalAddress, SMALL_PAGE_SIZE};
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameOwner {
+    Free,
+    Reserved, // Not usable memory, such as firmware memory
+    Kernel, // Kernel image
+    KernelHeap,
+    PageTable,
+    User,
+    Dma,
+    Cache // Memory that can be reclaimed
+}
+
+pub const FRAME_OWNERS: [FrameOwner; 8] = [
+    FrameOwner::Free,
+    FrameOwner::Reserved,
+    FrameOwner::Kernel,
+    FrameOwner::KernelHeap,
+    FrameOwner::PageTable,
+    FrameOwner::User,
+    FrameOwner::Dma,
+    FrameOwner::Cache
+];
+
+bitflags! {
+    #[derive(Clone, Copy, Debug)]
+    pub struct FrameFlags: u8 {
+        const Pinned = 1 << 0; // The frame must not be freed or moved, because a device may access it
+    }
+}
+
+// Metadata of a small page (4 KiB) of physical memory
+#[derive(Clone, Copy)]
+pub struct Frame {
+    pub reference_count: u32, // Number of mappings that point to the frame
+    pub owner: FrameOwner,
+    pub flags: FrameFlags
+}
+
+impl Frame {
+    const fn new(owner: FrameOwner, reference_count: u32) -> Frame {
+        Self { reference_count, owner, flags: FrameFlags::empty() }
+    }
+}
+
+pub struct FrameDatabase {
+    frames: &'static mut [Frame] // Indexed by the physical address divided by the small page size
+}
+
+impl FrameDatabase {
+    const fn new() -> FrameDatabase {
+        Self { frames: &mut [] }
+    }
+
+    // Note: Frames outside the database, such as memory mapped devices, have no metadata
+    fn get_frames_mut(&mut self, address: PhysicalAddress, size: usize) -> &mut [Frame] {
+        let start = (address.value() / SMALL_PAGE_SIZE).min(self.frames.len());
+        let end = (address.value() + size).div_ceil(SMALL_PAGE_SIZE).min(self.frames.len());
+
+        &mut self.frames[start..end]
+    }
+
+    pub fn get(&self, address: PhysicalAddress) -> Option<Frame> {
+        self.frames.get(address.value() / SMALL_PAGE_SIZE).copied()
+    }
+
+    pub fn allocated(&mut self, address: PhysicalAddress, size: usize) {
+        for frame in self.get_frames_mut(address, size) {
+            *frame = Frame::new(FrameOwner::KernelHeap, 1);
+        }
+    }
+
+    pub fn deallocated(&mut self, address: PhysicalAddress, size: usize) {
+        for frame in self.get_frames_mut(address, size) {
+            assert!(!frame.flags.contains(FrameFlags::Pinned), "Frame database: Freed a pinned frame");
+            *frame = Frame::new(FrameOwner::Free, 0);
+        }
+    }
+
+    // Cached frames are still free memory, which a processor keeps for its next allocations
+    pub fn cached(&mut self, address: PhysicalAddress, size: usize) {
+        for frame in self.get_frames_mut(address, size) {
+            assert!(!frame.flags.contains(FrameFlags::Pinned), "Frame database: Cached a pinned frame");
+            *frame = Frame::new(FrameOwner::Cache, 0);
+        }
+    }
+
+    pub fn set_owner(&mut self, address: PhysicalAddress, size: usize, owner: FrameOwner) {
+        for frame in self.get_frames_mut(address, size) {
+            frame.owner = owner;
+        }
+    }
+
+    pub fn set_flags(&mut self, address: PhysicalAddress, size: usize, flags: FrameFlags, enabled: bool) {
+        for frame in self.get_frames_mut(address, size) {
+            frame.flags.set(flags, enabled);
+        }
+    }
+
+    // Adds a mapping to the frame and returns the new reference count
+    pub fn reference(&mut self, address: PhysicalAddress) -> u32 {
+        let frame = self.get_frames_mut(address, 1).first_mut().expect("Frame database: Frame has no metadata");
+        assert!(!matches!(frame.owner, FrameOwner::Free | FrameOwner::Cache), "Frame database: Referenced a free frame");
+
+        frame.reference_count += 1;
+        frame.reference_count
+    }
+
+    // Removes a mapping from the frame and returns the new reference count, the frame should be freed once it reaches zero
+    pub fn release(&mut self, address: PhysicalAddress) -> u32 {
+        let frame = self.get_frames_mut(address, 1).first_mut().expect("Frame database: Frame has no metadata");
+        assert!(frame.reference_count > 0, "Frame database: Released a frame that has no references");
+
+        frame.reference_count -= 1;
+        frame.reference_count
+    }
+
+    // Prints how many frames each owner has in every region
+    pub fn print_ownership(&self, regions: &Regions) {
+        for index in 0..regions.length {
+            let region = unsafe { *regions.data.add(index) };
+            let mut counts = [0; FRAME_OWNERS.len()];
+
+            let start = (region.start / SMALL_PAGE_SIZE).min(self.frames.len());
+            let end = region.end.div_ceil(SMALL_PAGE_SIZE).min(self.frames.len());
+
+            for frame in &self.frames[start..end] {
+                counts[frame.owner as usize] += 1;
+            }
+
+            debug_write_line!("Frame database: Region {:#X}-{:#X}:", region.start, region.end);
+
+            for (owner, count) in FRAME_OWNERS.iter().zip(counts) {
+                if count > 0 {
+                    debug_write_line!("Frame database:     {:?}: {} frame(s)", owner, count);
+                }
+            }
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref instance: Mutex<FrameDatabase> = {
+        Mutex::new(FrameDatabase::new())
+    };
+}
+
+// Creates the metadata for all the available physical memory.
+// Note: Memory allocated before this is attributed to the kernel heap.
+pub fn initialize(regions: &Regions, kernel_regions: &Regions) {
+    let end = regions.find_end(|region| region.kind == RegionKind::Available);
+    let count = end.div_ceil(SMALL_PAGE_SIZE);
+
+    // Note: The database is filled before it is installed, because the buddy allocator updates the installed database
+    let frames: &'static mut [Frame] = Box::leak(vec![Frame::new(FrameOwner::Reserved, 0); count].into_boxed_slice());
+    let mut database = FrameDatabase { frames };
+
+    for (regions, owner) in [(regions, FrameOwner::KernelHeap), (kernel_regions, FrameOwner::Kernel)] {
+        for index in 0..regions.length {
+            let region = unsafe { *regions.data.add(index) };
+
+            if owner == FrameOwner::Kernel || region.kind == RegionKind::Available {
+                let frames = database.get_frames_mut(PhysicalAddress::new(region.start), region.size());
+                frames.fill(Frame::new(owner, 1));
+            }
+        }
+    }
+
+    physical_buddy_allocator::instance.lock().for_each_free_slab(|address, size| {
+        database.deallocated(address, size);
+    });
+
+    // Note: The pages cached by the processor are not free slabs of the buddy allocator, but they are not in use either
+    magazine::for_each_cached_page(|address, size| {
+        database.cached(address, size);
+    });
+
+    debug_write_line!("Frame database: Tracking {} frame(s) in {} byte(s)", count, count * size_of::<Frame>());
+
+    *instance.lock() = database;
+}
+
+pub fn get(address: PhysicalAddress) -> Option<Frame> {
+    instance.lock().get(address)
+}
+
+pub fn set_owner(address: PhysicalAddress, size: usize, owner: FrameOwner) {
+    instance.lock().set_owner(address, size, owner);
+}
+
+pub fn reference(address: PhysicalAddress) -> u32 {
+    instance.lock().reference(address)
+}
+
+pub fn release(address: PhysicalAddress) -> u32 {
+    instance.lock().release(address)
+}
@@ -0,0 +1,391 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr,
+    slice
+};
+
+use spin::Mutex;
+
+use crate::{debug, debug_write_line};
+
+// Bytes before and after each allocation that must stay untouched
+pub const RED_ZONE_SIZE: usize = 16;
+
+// Note: The patterns are distinct, so that a dump shows whether memory was overrun, uninitialized or freed
+const RED_ZONE_PATTERN: u8 = 0xfd;
+const ALLOCATED_PATTERN: u8 = 0xcd;
+const FREED_PATTERN: u8 = 0xdd;
+
+pub const MAX_TRACKED_ALLOCATION_COUNT: usize = 4096;
+
+// Replaces the start of the front red zone of the allocations that did not fit in the table, so that freeing them can be told
+// apart from freeing memory that was never allocated
+const UNTRACKED_MARKER: u64 = 0xfeedfacecafebeef;
+
+// Freed allocations are held back this long, so that writes after freeing are noticed before the memory is reused
+pub const QUARANTINE_SIZE: usize = 64;
+
+pub const CALLER_DEPTH: usize = 4;
+
+// Frames of the allocator itself and the allocation functions of the alloc crate
+const SKIPPED_FRAME_COUNT: usize = 3;
+
+#[derive(Clone, Copy)]
+pub struct AllocationRecord {
+    pub address: usize, // Address given to the caller
+    pub size: usize,
+    pub alignment: usize,
+    pub callers: [u64; CALLER_DEPTH] // Return addresses, the innermost caller first
+}
+
+impl AllocationRecord {
+    const fn empty() -> AllocationRecord {
+        Self { address: 0, size: 0, alignment: 0, callers: [0; CALLER_DEPTH] }
+    }
+
+    fn print(&self) {
+        debug_write_line!(
+            "Heap debug:     {:#X}: {} byte(s), callers={:X?}", self.address, self.size, self.callers
+        );
+    }
+}
+
+struct State {
+    allocations: [AllocationRecord; MAX_TRACKED_ALLOCATION_COUNT],
+    allocation_count: usize,
+    untracked_count: usize, // Allocations made while the table was full
+    quarantine: [AllocationRecord; QUARANTINE_SIZE],
+    quarantine_count: usize,
+    quarantine_next: usize // Oldest entry, which is released next
+}
+
+impl State {
+    // Returns false if the table is full
+    fn track(&mut self, record: AllocationRecord) -> bool {
+        if self.allocation_count == MAX_TRACKED_ALLOCATION_COUNT {
+            self.untracked_count += 1;
+            return false;
+        }
+
+        self.allocations[self.allocation_count] = record;
+        self.allocation_count += 1;
+        true
+    }
+
+    fn untrack(&mut self, address: usize) -> Option<AllocationRecord> {
+        let index = self.allocations[..self.allocation_count]
+            .iter()
+            .position(|record| record.address == address)?;
+
+        let record = self.allocations[index];
+        self.allocation_count -= 1;
+        self.allocations[index] = self.allocations[self.allocation_count];
+
+        Some(record)
+    }
+
+    // Returns the record that has to make room, if the quarantine is full
+    fn quarantine(&mut self, record: AllocationRecord) -> Option<AllocationRecord> {
+        if self.quarantine_count < QUARANTINE_SIZE {
+            self.quarantine[self.quarantine_count] = record;
+            self.quarantine_count += 1;
+            return None;
+        }
+
+        let released = self.quarantine[self.quarantine_next];
+        self.quarantine[self.quarantine_next] = record;
+        self.quarantine_next = (self.quarantine_next + 1) % QUARANTINE_SIZE;
+
+        Some(released)
+    }
+}
+
+// Wraps an allocator, so that each allocation is surrounded by red zones and freed memory is poisoned.
+// Note: The state lock is never held while calling the wrapped allocator, because it may allocate itself.
+pub struct HeapDebugAllocator<A: GlobalAlloc> {
+    inner: A,
+    state: Mutex<State>
+}
+
+impl<A: GlobalAlloc> HeapDebugAllocator<A> {
+    pub const fn new(inner: A) -> HeapDebugAllocator<A> {
+        Self {
+            inner,
+            state: Mutex::new(State {
+                allocations: [AllocationRecord::empty(); MAX_TRACKED_ALLOCATION_COUNT],
+                allocation_count: 0,
+                untracked_count: 0,
+                quarantine: [AllocationRecord::empty(); QUARANTINE_SIZE],
+                quarantine_count: 0,
+                quarantine_next: 0
+            })
+        }
+    }
+
+    // The front red zone is padded to the alignment, so that the address given to the caller stays aligned
+    fn get_front_size(alignment: usize) -> usize {
+        RED_ZONE_SIZE.max(alignment)
+    }
+
+    fn get_inner_layout(size: usize, alignment: usize) -> Layout {
+        let size = Self::get_front_size(alignment) + size + RED_ZONE_SIZE;
+        Layout::from_size_align(size, alignment).expect("Heap debug: Layout is too large")
+    }
+
+    fn get_bytes<'a>(address: usize, size: usize) -> &'a mut [u8] {
+        unsafe { slice::from_raw_parts_mut(address as *mut u8, size) }
+    }
+
+    fn get_front_red_zone<'a>(record: &AllocationRecord) -> &'a mut [u8] {
+        Self::get_bytes(record.address - RED_ZONE_SIZE, RED_ZONE_SIZE)
+    }
+
+    fn get_back_red_zone<'a>(record: &AllocationRecord) -> &'a mut [u8] {
+        Self::get_bytes(record.address + record.size, RED_ZONE_SIZE)
+    }
+
+    fn get_marker(address: usize) -> *mut u64 {
+        (address - RED_ZONE_SIZE) as *mut u64
+    }
+
+    // Restores the red zone under the marker, returns false if the allocation was not marked as untracked
+    fn take_untracked_marker(address: usize) -> bool {
+        let marker = Self::get_marker(address);
+
+        unsafe {
+            if marker.read_unaligned() != UNTRACKED_MARKER {
+                return false;
+            }
+
+            marker.write_unaligned(u64::from_ne_bytes([RED_ZONE_PATTERN; 8]));
+        }
+
+        true
+    }
+
+    // Returns whether both red zones of the allocation are intact
+    fn check_red_zones(record: &AllocationRecord) -> bool {
+        let is_intact = |bytes: &[u8]| bytes.iter().all(|byte| *byte == RED_ZONE_PATTERN);
+        is_intact(Self::get_front_red_zone(record)) && is_intact(Self::get_back_red_zone(record))
+    }
+
+    fn report_corruption(record: &AllocationRecord, message: &str) -> ! {
+        debug_write_line!("Heap debug: {} at {:#X}, allocated by:", message, record.address);
+        record.print();
+        panic!("Heap debug: {} at {:#X}", message, record.address);
+    }
+
+    // Gives the allocation back to the wrapped allocator once it leaves the quarantine
+    unsafe fn release(&self, record: &AllocationRecord) {
+        if Self::get_bytes(record.address, record.size).iter().any(|byte| *byte != FREED_PATTERN) {
+            Self::report_corruption(record, "Freed memory was written");
+        }
+
+        let front_size = Self::get_front_size(record.alignment);
+        let layout = Self::get_inner_layout(record.size, record.alignment);
+        self.inner.dealloc((record.address - front_size) as *mut u8, layout);
+    }
+
+    // Prints the live allocations and verifies their red zones, returns the number of corrupted allocations
+    pub fn report(&self) -> usize {
+        let state = self.state.lock();
+        let mut corrupted_count = 0;
+
+        debug_write_line!(
+            "Heap debug: {} live allocation(s), {} untracked", state.allocation_count, state.untracked_count
+        );
+
+        for record in &state.allocations[..state.allocation_count] {
+            record.print();
+
+            if !Self::check_red_zones(record) {
+                debug_write_line!("Heap debug:     Red zone of {:#X} was overwritten", record.address);
+                corrupted_count += 1;
+            }
+        }
+
+        corrupted_count
+    }
+
+    pub fn live_allocation_count(&self) -> usize {
+        let state = self.state.lock();
+        state.allocation_count + state.untracked_count
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for HeapDebugAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let front_size = Self::get_front_size(layout.align());
+        let block = self.inner.alloc(Self::get_inner_layout(layout.size(), layout.align()));
+
+        if block.is_null() {
+            return ptr::null_mut();
+        }
+
+        let mut record = AllocationRecord {
+            address: block as usize + front_size,
+            size: layout.size(),
+            alignment: layout.align(),
+            callers: [0; CALLER_DEPTH]
+        };
+
+        debug::capture_return_addresses(SKIPPED_FRAME_COUNT, &mut record.callers);
+
+        Self::get_bytes(block as usize, front_size).fill(RED_ZONE_PATTERN);
+        Self::get_bytes(record.address, record.size).fill(ALLOCATED_PATTERN);
+        Self::get_back_red_zone(&record).fill(RED_ZONE_PATTERN);
+
+        if !self.state.lock().track(record) {
+            Self::get_marker(record.address).write_unaligned(UNTRACKED_MARKER);
+        }
+
+        record.address as *mut u8
+    }
+
+    unsafe fn dealloc(&self, address: *mut u8, layout: Layout) {
+        let mut state = self.state.lock();
+
+        let record = match state.untrack(address as usize) {
+            Some(record) => record,
+            // The allocation may have been made while the table was full
+            None if Self::take_untracked_marker(address as usize) => {
+                state.untracked_count -= 1;
+                AllocationRecord { address: address as usize, size: layout.size(), alignment: layout.align(), callers: [0; CALLER_DEPTH] }
+            },
+            None => {
+                drop(state);
+                panic!("Heap debug: Freed {:#X}, which was not allocated", address as usize);
+            }
+        };
+
+        drop(state);
+
+        if record.size != layout.size() || record.alignment != layout.align() {
+            Self::report_corruption(&record, "Freed with a different layout");
+        }
+
+        if !Self::check_red_zones(&record) {
+            Self::report_corruption(&record, "Red zone was overwritten");
+        }
+
+        Self::get_bytes(record.address, record.size).fill(FREED_PATTERN);
+
+        let released = self.state.lock().quarantine(record);
+
+        if let Some(released) = released {
+            self.release(&released);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{alloc::System, vec::Vec};
+
+    use super::*;
+
+    fn allocate(allocator: &HeapDebugAllocator<System>, size: usize, alignment: usize) -> *mut u8 {
+        let address = unsafe { allocator.alloc(Layout::from_size_align(size, alignment).unwrap()) };
+        assert!(!address.is_null());
+        address
+    }
+
+    fn deallocate(allocator: &HeapDebugAllocator<System>, address: *mut u8, size: usize, alignment: usize) {
+        unsafe { allocator.dealloc(address, Layout::from_size_align(size, alignment).unwrap()) };
+    }
+
+    #[test]
+    fn tracks_live_allocations() {
+        let allocator = HeapDebugAllocator::new(System);
+        let mut addresses = Vec::new();
+
+        for alignment in [1, 8, 64, 4096] {
+            let address = allocate(&allocator, 24, alignment);
+            assert_eq!(address as usize % alignment, 0);
+            addresses.push((address, alignment));
+        }
+
+        assert_eq!(allocator.live_allocation_count(), 4);
+        assert_eq!(allocator.report(), 0);
+
+        for (address, alignment) in addresses {
+            deallocate(&allocator, address, 24, alignment);
+        }
+
+        assert_eq!(allocator.live_allocation_count(), 0);
+    }
+
+    #[test]
+    fn reports_overruns_of_live_allocations() {
+        let allocator = HeapDebugAllocator::new(System);
+        let address = allocate(&allocator, 16, 8);
+
+        unsafe { *address.add(16) = 0 };
+
+        assert_eq!(allocator.report(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Red zone was overwritten")]
+    fn detects_underruns_when_freeing() {
+        let allocator = HeapDebugAllocator::new(System);
+        let address = allocate(&allocator, 16, 8);
+
+        unsafe { *address.sub(1) = 0 };
+
+        deallocate(&allocator, address, 16, 8);
+    }
+
+    #[test]
+    #[should_panic(expected = "Freed memory was written")]
+    fn detects_writes_after_freeing() {
+        let allocator = HeapDebugAllocator::new(System);
+        let address = allocate(&allocator, 16, 8);
+        deallocate(&allocator, address, 16, 8);
+
+        unsafe { *address = 0 };
+
+        // Push the allocation out of the quarantine
+        for _ in 0..QUARANTINE_SIZE {
+            let other = allocate(&allocator, 16, 8);
+            deallocate(&allocator, other, 16, 8);
+        }
+    }
+
+    #[test]
+    fn frees_untracked_allocations() {
+        let allocator = HeapDebugAllocator::new(System);
+        let addresses: Vec<_> = (0..=MAX_TRACKED_ALLOCATION_COUNT).map(|_| allocate(&allocator, 16, 8)).collect();
+
+        assert_eq!(allocator.live_allocation_count(), MAX_TRACKED_ALLOCATION_COUNT + 1);
+
+        for address in addresses.into_iter().rev() {
+            deallocate(&allocator, address, 16, 8);
+        }
+
+        assert_eq!(allocator.live_allocation_count(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "which was not allocated")]
+    fn detects_freeing_unknown_memory_while_the_table_is_full() {
+        let allocator = HeapDebugAllocator::new(System);
+
+        for _ in 0..=MAX_TRACKED_ALLOCATION_COUNT {
+            allocate(&allocator, 16, 8);
+        }
+
+        let mut memory = [RED_ZONE_PATTERN; 64];
+        deallocate(&allocator, unsafe { memory.as_mut_ptr().add(32) }, 16, 8);
+    }
+
+    #[test]
+    #[should_panic(expected = "which was not allocated")]
+    fn detects_double_free() {
+        let allocator = HeapDebugAllocator::new(System);
+        let address = allocate(&allocator, 16, 8);
+
+        deallocate(&allocator, address, 16, 8);
+        deallocate(&allocator, address, 16, 8);
+    }
+}
@@ -0,0 +1,245 @@
+use alloc::vec::Vec;
+use core::{mem, ptr};
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::{debug_write_line, low::x64::kernel_paging_table};
+
+use super::{
+    kernel_virtual_allocator::KernelVirtualAllocator,
+    mapper::{IO_REMAP_REGION_BASE, IO_REMAP_REGION_SIZE},
+    paging_table::{CacheType, PagingFlags},
+    tlb,
+    PhysicalAddress, VirtualAddress, SMALL_PAGE_SIZE
+};
+
+const CACHE_FLAGS: PagingFlags = PagingFlags::NoCache
+    .union(PagingFlags::WriteCombining)
+    .union(PagingFlags::WriteThrough);
+
+lazy_static! {
+    static ref ranges: Mutex<KernelVirtualAllocator> = {
+        Mutex::new(KernelVirtualAllocator::new(IO_REMAP_REGION_BASE, IO_REMAP_REGION_SIZE))
+    };
+
+    static ref mappings: Mutex<Vec<ActiveMapping>> = {
+        Mutex::new(Vec::new())
+    };
+}
+
+// The physical range of a live mapping and the cache flags its alias in the kernel map had before any mapping covered it
+struct ActiveMapping {
+    start: VirtualAddress,
+    physical_start: PhysicalAddress,
+    cache_type: CacheType,
+    previous_cache_flags: Vec<Option<PagingFlags>> // One per page, None where the kernel map does not cover the page
+}
+
+impl ActiveMapping {
+    // Returns the index of the physical page within the mapping, if the mapping covers it
+    fn page_index(&self, physical_page: PhysicalAddress) -> Option<usize> {
+        let index = physical_page.value().checked_sub(self.physical_start.value())? / SMALL_PAGE_SIZE;
+        (index < self.previous_cache_flags.len()).then_some(index)
+    }
+
+    fn overlaps(&self, physical_start: PhysicalAddress, size: usize) -> bool {
+        let end = self.physical_start.value() + self.previous_cache_flags.len() * SMALL_PAGE_SIZE;
+        physical_start.value() < end && self.physical_start.value() < physical_start.value() + size
+    }
+}
+
+// Device memory mapped with a cache type, which is unmapped when dropped.
+// Note: All the accesses are volatile, so that the compiler neither merges nor reorders them.
+pub struct IoMapping {
+    start: VirtualAddress, // Start of the mapped pages
+    physical_start: PhysicalAddress,
+    offset: usize, // Offset of the physical address within the first page
+    size: usize,
+    cache_type: CacheType
+}
+
+impl IoMapping {
+    pub fn address(&self) -> VirtualAddress {
+        VirtualAddress::new(self.start.value() + self.offset)
+    }
+
+    pub fn as_ptr<T>(&self) -> *mut T {
+        self.address().value() as *mut T
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn cache_type(&self) -> CacheType {
+        self.cache_type
+    }
+
+    fn get_pointer<T>(&self, offset: usize) -> *mut T {
+        assert!(offset + mem::size_of::<T>() <= self.size, "I/O remap: Access at {:#X} is out of bounds", offset);
+
+        let pointer = (self.address().value() + offset) as *mut T;
+        assert!(pointer.is_aligned(), "I/O remap: Access at {:#X} is not aligned", offset);
+
+        pointer
+    }
+
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { ptr::read_volatile(self.get_pointer(offset)) }
+    }
+
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        unsafe { ptr::write_volatile(self.get_pointer(offset), value) }
+    }
+
+    fn page_count(&self) -> usize {
+        (self.offset + self.size).div_ceil(SMALL_PAGE_SIZE)
+    }
+}
+
+impl Drop for IoMapping {
+    fn drop(&mut self) {
+        debug_write_line!("I/O remap: Unmapping {:#X} at {:#X}", self.physical_start.value() + self.offset, self.address().value());
+
+        let size = self.page_count() * SMALL_PAGE_SIZE;
+        kernel_paging_table().unmap(self.start, size, PagingFlags::empty());
+
+        let mut active_mappings = mappings.lock();
+        let index = active_mappings.iter().position(|mapping| mapping.start == self.start).unwrap();
+        let mapping = active_mappings.swap_remove(index);
+
+        // The alias keeps the cache type as long as another mapping covers the page
+        set_direct_map_cache_flags(self.physical_start, size, |index| {
+            let page = PhysicalAddress::new(self.physical_start.value() + index * SMALL_PAGE_SIZE);
+
+            if active_mappings.iter().any(|other| other.page_index(page).is_some()) {
+                None
+            } else {
+                mapping.previous_cache_flags[index]
+            }
+        });
+
+        drop(active_mappings);
+        ranges.lock().deallocate_range(self.start, size);
+    }
+}
+
+// Changes the cache flags of the pages that alias the physical range in the kernel map to the ones returned for the
+// index of each page, which leaves the page unchanged when it returns None.
+// Returns the previous cache flags of each page, which are None where the kernel map does not cover the page.
+// Mapping the same memory with different cache types is undefined behavior on x64, so both must agree.
+fn set_direct_map_cache_flags(
+    physical_start: PhysicalAddress,
+    size: usize,
+    mut cache_flags: impl FnMut(usize) -> Option<PagingFlags>
+) -> Vec<Option<PagingFlags>> {
+    let mut paging_table = kernel_paging_table();
+    let start = VirtualAddress::to_kernel(physical_start);
+    let mut previous_cache_flags = Vec::with_capacity(size / SMALL_PAGE_SIZE);
+    let mut changed = false;
+
+    for (index, offset) in (0..size).step_by(SMALL_PAGE_SIZE).enumerate() {
+        let address = VirtualAddress::new(start.value() + offset);
+
+        // Note: The kernel map only covers the available memory, devices are usually above it
+        let Some((_, flags)) = paging_table.translate(address) else {
+            previous_cache_flags.push(None);
+            continue;
+        };
+
+        previous_cache_flags.push(Some(flags.intersection(CACHE_FLAGS)));
+
+        let Some(new_cache_flags) = cache_flags(index) else {
+            continue;
+        };
+
+        let flags = flags.difference(CACHE_FLAGS) | new_cache_flags | PagingFlags::NoFlush;
+        paging_table.protect(address, SMALL_PAGE_SIZE, flags).expect("I/O remap: Kernel map page was executable");
+        changed = true;
+    }
+
+    if changed {
+        tlb::flush_range(start, size);
+    }
+
+    previous_cache_flags
+}
+
+// Maps the physical range with the cache type.
+// Returns None if there are not enough virtual addresses or a part of the range is already mapped with another cache type.
+pub fn ioremap(physical_address: PhysicalAddress, size: usize, cache_type: CacheType) -> Option<IoMapping> {
+    assert!(size != 0, "I/O remap: Size was zero");
+
+    let physical_start = physical_address.align(SMALL_PAGE_SIZE);
+    let offset = physical_address.value() - physical_start.value();
+    let mapped_size = (offset + size).next_multiple_of(SMALL_PAGE_SIZE);
+
+    // Note: Held until the mapping is recorded, so that no conflicting mapping can be made in the meantime
+    let mut active_mappings = mappings.lock();
+
+    let conflict = active_mappings
+        .iter()
+        .find(|mapping| mapping.overlaps(physical_start, mapped_size) && mapping.cache_type != cache_type);
+
+    if let Some(mapping) = conflict {
+        debug_write_line!(
+            "I/O remap: {:#X}-{:#X} is already mapped as {:?}",
+            physical_address.value(),
+            physical_address.value() + size,
+            mapping.cache_type
+        );
+        return None;
+    }
+
+    let Some(start) = ranges.lock().allocate_range(mapped_size, SMALL_PAGE_SIZE) else {
+        debug_write_line!("I/O remap: Out of virtual memory");
+        return None;
+    };
+
+    debug_write_line!(
+        "I/O remap: Mapping {:#X}-{:#X} as {:?} at {:#X}",
+        physical_address.value(),
+        physical_address.value() + size,
+        cache_type,
+        start.value() + offset
+    );
+
+    // Change the alias first, so that no cacheable alias exists while the new mapping is in use
+    let mut previous_cache_flags = set_direct_map_cache_flags(physical_start, mapped_size, |_| Some(cache_type.flags()));
+
+    // The pages that other mappings cover have the cache type already, so the flags from before those mappings are kept
+    for (index, previous) in previous_cache_flags.iter_mut().enumerate() {
+        let page = PhysicalAddress::new(physical_start.value() + index * SMALL_PAGE_SIZE);
+
+        let original = active_mappings
+            .iter()
+            .find_map(|other| other.page_index(page).map(|other_index| other.previous_cache_flags[other_index]));
+
+        if let Some(original) = original {
+            *previous = original;
+        }
+    }
+
+    // Note: The lock must not be held here, because mapping may allocate new paging tables
+    let mut paging_table = kernel_paging_table();
+
+    for page in (0..mapped_size).step_by(SMALL_PAGE_SIZE) {
+        paging_table.map_page(
+            VirtualAddress::new(start.value() + page),
+            PhysicalAddress::new(physical_start.value() + page),
+            cache_type.flags() | PagingFlags::NoExecute | PagingFlags::NoFlush
+        ).unwrap();
+    }
+
+    // The addresses may have been mapped before, and other processors may still cache the old translations
+    tlb::flush_range(start, mapped_size);
+
+    active_mappings.push(ActiveMapping { start, physical_start, cache_type, previous_cache_flags });
+    Some(IoMapping { start, physical_start, offset, size, cache_type })
+}
+
+pub fn iounmap(mapping: IoMapping) {
+    drop(mapping);
+}
+
@@ -1,24 +1,112 @@
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
-use super::physical_buddy_allocator;
+#[cfg(feature = "heap-debug")]
+use super::heap_debug::HeapDebugAllocator;
+use super::{
+    kernel_virtual_allocator,
+    magazine,
+    physical_buddy_allocator::{self, L0_SIZE},
+    physical_slab_allocator::{self, PhysicalSlabAllocator},
+    reclaim
+};
+
+#[derive(PartialEq)]
+enum AllocatorKind {
+    Slab,
+    Buddy,
+    Virtual
+}
 
 struct KernelAllocator {}
 
+impl KernelAllocator {
+    fn get_allocator_kind(layout: Layout) -> AllocatorKind {
+        if PhysicalSlabAllocator::is_suitable(layout) {
+            // Small allocations would waste most of a buddy slab, so they are served from the slab caches
+            AllocatorKind::Slab
+        } else if layout.size().max(layout.align()) <= L0_SIZE {
+            AllocatorKind::Buddy
+        } else {
+            // No single slab can hold the allocation, so it must be stitched together from multiple slabs
+            AllocatorKind::Virtual
+        }
+    }
+}
+
 unsafe impl GlobalAlloc for KernelAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        physical_buddy_allocator::instance.lock().allocate(layout)
+        // Note: Each allocator attempts to reclaim memory before failing
+        let address = match Self::get_allocator_kind(layout) {
+            AllocatorKind::Slab => magazine::allocate_object(layout)
+                .unwrap_or_else(|| physical_slab_allocator::instance.lock().allocate(layout)),
+            AllocatorKind::Buddy => physical_buddy_allocator::try_allocate(layout).unwrap_or(ptr::null_mut()),
+            AllocatorKind::Virtual => kernel_virtual_allocator::allocate(layout)
+        };
+
+        // Report the state of the memory, because returning null ends up in the allocation error handler
+        if address.is_null() {
+            reclaim::report_out_of_memory(layout);
+        }
+
+        address
     }
 
     unsafe fn dealloc(&self, address: *mut u8, layout: Layout) {
-        physical_buddy_allocator::instance.lock().deallocate(address, layout)
+        match Self::get_allocator_kind(layout) {
+            AllocatorKind::Slab => {
+                if !magazine::deallocate_object(address, layout) {
+                    physical_slab_allocator::instance.lock().deallocate(address, layout);
+                }
+            },
+            AllocatorKind::Buddy => physical_buddy_allocator::deallocate(address, layout),
+            AllocatorKind::Virtual => kernel_virtual_allocator::deallocate(address, layout)
+        }
     }
 
-    unsafe fn realloc(&self, _ptr: *mut u8, _layout: Layout, _new_size: usize) -> *mut u8 {
-        // Optional: Implement if resizing is needed
-        ptr::null_mut()
+    unsafe fn realloc(&self, address: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let kind = Self::get_allocator_kind(layout);
+
+        if kind == Self::get_allocator_kind(new_layout) {
+            match kind {
+                // Objects of the same size class share the slot size, so the object can stay where it is
+                AllocatorKind::Slab if PhysicalSlabAllocator::is_same_size_class(layout, new_layout) => {
+                    return address;
+                },
+                // Attempt to merge the following buddy slabs into the allocation
+                AllocatorKind::Buddy if physical_buddy_allocator::instance.lock().try_resize_in_place(address, new_layout) => {
+                    return address;
+                },
+                _ => {}
+            }
+        }
+
+        // Move the allocation, because it could not be resized in place
+        let new_address = self.alloc(new_layout);
+
+        if new_address.is_null() {
+            return ptr::null_mut();
+        }
+
+        ptr::copy_nonoverlapping(address, new_address, layout.size().min(new_size));
+        self.dealloc(address, layout);
+
+        new_address
     }
 }
 
+#[cfg(not(feature = "heap-debug"))]
 #[global_allocator]
-static ALLOCATOR: KernelAllocator = KernelAllocator {};
\ No newline at end of file
+static ALLOCATOR: KernelAllocator = KernelAllocator {};
+
+#[cfg(feature = "heap-debug")]
+#[global_allocator]
+static ALLOCATOR: HeapDebugAllocator<KernelAllocator> = HeapDebugAllocator::new(KernelAllocator {});
+
+// Prints the live allocations and panics if any of them was overrun
+#[cfg(feature = "heap-debug")]
+pub fn report() {
+    let corrupted_count = ALLOCATOR.report();
+    assert!(corrupted_count == 0, "Kernel allocator: {} allocation(s) were overrun", corrupted_count);
+}
@@ -0,0 +1,82 @@
+use core::ops::Range;
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::debug_write_line;
+
+use super::{
+    kernel_virtual_allocator::{self, KernelVirtualAllocator},
+    mapper::{self, KERNEL_STACK_REGION_BASE, KERNEL_STACK_REGION_SIZE},
+    KiB, VirtualAddress, SMALL_PAGE_SIZE
+};
+
+pub const DEFAULT_STACK_SIZE: usize = 64 * KiB;
+
+// Each stack is preceded by an unmapped page, so that overflowing the stack faults instead of corrupting the memory below it
+pub const GUARD_SIZE: usize = SMALL_PAGE_SIZE;
+
+const REGION: Range<usize> = KERNEL_STACK_REGION_BASE..(KERNEL_STACK_REGION_BASE + KERNEL_STACK_REGION_SIZE);
+
+lazy_static! {
+    static ref ranges: Mutex<KernelVirtualAllocator> = {
+        Mutex::new(KernelVirtualAllocator::new(KERNEL_STACK_REGION_BASE, KERNEL_STACK_REGION_SIZE))
+    };
+}
+
+pub struct KernelStack {
+    bottom: VirtualAddress, // The guard page is right below the bottom
+    size: usize
+}
+
+impl KernelStack {
+    // Returns None if there is not enough memory
+    pub fn allocate(size: usize) -> Option<KernelStack> {
+        let size = size.next_multiple_of(SMALL_PAGE_SIZE);
+
+        let Some(start) = ranges.lock().allocate_range(GUARD_SIZE + size, SMALL_PAGE_SIZE) else {
+            debug_write_line!("Kernel stack: Out of virtual memory");
+            return None;
+        };
+
+        let bottom = VirtualAddress::new(start.value() + GUARD_SIZE);
+
+        // Note: The lock must not be held here, because mapping may allocate new paging tables
+        if !kernel_virtual_allocator::populate(bottom, size) {
+            ranges.lock().deallocate_range(start, GUARD_SIZE + size);
+            return None;
+        }
+
+        debug_write_line!("Kernel stack: Allocated a {} byte stack at {:#X}-{:#X}", size, bottom.value(), bottom.value() + size);
+
+        Some(Self { bottom, size })
+    }
+
+    pub fn bottom(&self) -> VirtualAddress {
+        self.bottom
+    }
+
+    // Stacks grow downwards, so this is the initial stack pointer
+    pub fn top(&self) -> VirtualAddress {
+        VirtualAddress::new(self.bottom.value() + self.size)
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        debug_write_line!("Kernel stack: Freeing the stack at {:#X}", self.bottom.value());
+
+        kernel_virtual_allocator::release(self.bottom, self.size);
+        ranges.lock().deallocate_range(VirtualAddress::new(self.bottom.value() - GUARD_SIZE), GUARD_SIZE + self.size);
+    }
+}
+
+// Returns whether accessing the address means that a stack has overflowed.
+// Note: Only the stacks are mapped in the region, so every other address is either a guard page or unused.
+pub fn is_overflow(address: VirtualAddress) -> bool {
+    REGION.contains(&address.value()) && mapper::translate(address).is_none()
+}
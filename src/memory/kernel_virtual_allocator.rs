@@ -0,0 +1,266 @@
+use core::{alloc::Layout, ptr};
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::{debug_write_line, low::x64::kernel_paging_table};
+
+use super::{
+    mapper::{KERNEL_VIRTUAL_REGION_BASE, KERNEL_VIRTUAL_REGION_SIZE},
+    paging_table::PagingFlags,
+    physical_buddy_allocator::{self, L0_SIZE},
+    PhysicalAddress, VirtualAddress, SMALL_PAGE_SIZE
+};
+
+// Note: The list also limits how many ranges can be allocated at once, see `allocation_count`.
+// Todo: Should we grow the free range list dynamically?
+pub const MAX_FREE_RANGE_COUNT: usize = 256;
+
+// Each allocation is followed by an unmapped page, so that overruns fault instead of corrupting the next allocation
+pub const GUARD_SIZE: usize = SMALL_PAGE_SIZE;
+
+#[derive(Clone, Copy)]
+struct Range {
+    start: usize,
+    end: usize
+}
+
+impl Range {
+    const fn empty() -> Range {
+        Self { start: 0, end: 0 }
+    }
+}
+
+pub struct KernelVirtualAllocator {
+    free: [Range; MAX_FREE_RANGE_COUNT], // Sorted by the start address
+    free_count: usize,
+    // Free ranges are merged on deallocation, so an allocation separates every two of them and there is at most
+    // one more free range than allocations. Limiting the allocations keeps the free ranges within the list.
+    allocation_count: usize
+}
+
+impl KernelVirtualAllocator {
+    // Hands out virtual addresses from the region
+    pub fn new(start: usize, size: usize) -> KernelVirtualAllocator {
+        let mut allocator = Self { free: [Range::empty(); MAX_FREE_RANGE_COUNT], free_count: 1, allocation_count: 0 };
+        allocator.free[0] = Range { start, end: start + size };
+        allocator
+    }
+
+    fn insert_free_range(&mut self, index: usize, range: Range) {
+        assert!(self.free_count < MAX_FREE_RANGE_COUNT, "Kernel virtual allocator: Free range list overflowed");
+
+        self.free.copy_within(index..self.free_count, index + 1);
+        self.free[index] = range;
+        self.free_count += 1;
+    }
+
+    fn remove_free_range(&mut self, index: usize) {
+        self.free.copy_within((index + 1)..self.free_count, index);
+        self.free_count -= 1;
+    }
+
+    pub fn allocate_range(&mut self, size: usize, alignment: usize) -> Option<VirtualAddress> {
+        if self.allocation_count + 1 == MAX_FREE_RANGE_COUNT {
+            debug_write_line!("Kernel virtual allocator: Free range list is full");
+            return None;
+        }
+
+        for index in 0..self.free_count {
+            let range = self.free[index];
+            let start = range.start.next_multiple_of(alignment);
+
+            if start + size > range.end {
+                continue;
+            }
+
+            // Give the unused parts before and after the allocation back to the free ranges
+            let before = Range { start: range.start, end: start };
+            let after = Range { start: start + size, end: range.end };
+
+            self.remove_free_range(index);
+
+            if after.start != after.end {
+                self.insert_free_range(index, after);
+            }
+
+            if before.start != before.end {
+                self.insert_free_range(index, before);
+            }
+
+            self.allocation_count += 1;
+            return Some(VirtualAddress::new(start));
+        }
+
+        None
+    }
+
+    pub fn deallocate_range(&mut self, start: VirtualAddress, size: usize) {
+        let mut range = Range { start: start.value(), end: start.value() + size };
+        self.allocation_count -= 1;
+
+        // Find the position of the range, so that the free ranges stay sorted
+        let mut index = 0;
+
+        while index < self.free_count && self.free[index].start < range.start {
+            index += 1;
+        }
+
+        // Merge with the following free range
+        if index < self.free_count && self.free[index].start == range.end {
+            range.end = self.free[index].end;
+            self.remove_free_range(index);
+        }
+
+        // Merge with the preceding free range
+        if index > 0 && self.free[index - 1].end == range.start {
+            self.free[index - 1].end = range.end;
+            return;
+        }
+
+        self.insert_free_range(index, range);
+    }
+
+    fn get_size(layout: Layout) -> usize {
+        layout.size().next_multiple_of(SMALL_PAGE_SIZE)
+    }
+
+    fn get_chunk_size(remaining: usize) -> usize {
+        // Use the largest slabs possible, so that we touch the buddy allocator as rarely as possible.
+        // Note: The remaining size is a multiple of a small page, so the result is at least a small page.
+        let power_of_two = 1 << (usize::BITS - 1 - remaining.leading_zeros());
+        power_of_two.min(L0_SIZE)
+    }
+}
+
+lazy_static! {
+    pub static ref instance: Mutex<KernelVirtualAllocator> = {
+        Mutex::new(KernelVirtualAllocator::new(KERNEL_VIRTUAL_REGION_BASE, KERNEL_VIRTUAL_REGION_SIZE))
+    };
+}
+
+// Returns null if there is not enough memory
+pub fn allocate(layout: Layout) -> *mut u8 {
+    let size = KernelVirtualAllocator::get_size(layout);
+    let alignment = layout.align().max(SMALL_PAGE_SIZE);
+
+    let Some(start) = instance.lock().allocate_range(size + GUARD_SIZE, alignment) else {
+        debug_write_line!("Kernel virtual allocator: Out of virtual memory");
+        return ptr::null_mut();
+    };
+
+    debug_write_line!("Kernel virtual allocator: Allocating {} byte(s) at {:#X}", size, start.value());
+
+    // Note: The lock must not be held here, because mapping may allocate new paging tables
+    if !populate(start, size) {
+        instance.lock().deallocate_range(start, size + GUARD_SIZE);
+        return ptr::null_mut();
+    }
+
+    start.value() as *mut u8
+}
+
+// Maps physical memory to the specified virtual addresses, returns false if there is not enough memory
+pub fn populate(start: VirtualAddress, size: usize) -> bool {
+    let mut paging_table = kernel_paging_table();
+    let mut offset = 0;
+
+    while offset < size {
+        let chunk_size = KernelVirtualAllocator::get_chunk_size(size - offset);
+        let chunk_layout = Layout::from_size_align(chunk_size, chunk_size).unwrap();
+
+        let Ok(chunk) = physical_buddy_allocator::try_allocate(chunk_layout) else {
+            // Release the chunks mapped so far.
+            // Note: The chunks are computed from the largest to the smallest, so the mapped part is released the same way.
+            release(start, offset);
+            return false;
+        };
+
+        let physical_address = PhysicalAddress::from(VirtualAddress::new(chunk as usize));
+
+        for page in (0..chunk_size).step_by(SMALL_PAGE_SIZE) {
+            paging_table.map_page(
+                VirtualAddress::new(start.value() + offset + page),
+                PhysicalAddress::new(physical_address.value() + page),
+                PagingFlags::NoExecute | PagingFlags::NoFlush
+            ).unwrap();
+        }
+
+        offset += chunk_size;
+    }
+
+    true
+}
+
+// Unmaps the memory mapped by `populate` and returns its slabs to the buddy allocator
+pub fn release(start: VirtualAddress, size: usize) {
+    let mut paging_table = kernel_paging_table();
+    let mut offset = 0;
+
+    // The chunks are computed the same way as when allocating, so that each slab is returned as a whole
+    while offset < size {
+        let chunk_size = KernelVirtualAllocator::get_chunk_size(size - offset);
+        let mut chunk = None;
+
+        for page in (0..chunk_size).step_by(SMALL_PAGE_SIZE) {
+            let physical_address = paging_table.unmap_page(
+                VirtualAddress::new(start.value() + offset + page),
+                PagingFlags::NoFlush
+            );
+
+            chunk = chunk.or(physical_address);
+        }
+
+        let chunk = chunk.expect("Kernel virtual allocator: Deallocated memory was not mapped");
+        let chunk_layout = Layout::from_size_align(chunk_size, chunk_size).unwrap();
+        physical_buddy_allocator::deallocate(VirtualAddress::to_kernel(chunk).value() as *mut u8, chunk_layout);
+
+        offset += chunk_size;
+    }
+
+    // Flush once instead of after every page, so that the virtual addresses can be reused safely
+    paging_table.flush_range(start, size);
+}
+
+pub fn deallocate(address: *mut u8, layout: Layout) {
+    let start = VirtualAddress::new(address as usize);
+    let size = KernelVirtualAllocator::get_size(layout);
+
+    debug_write_line!("Kernel virtual allocator: Deallocating {} byte(s) at {:#X}", size, start.value());
+
+    release(start, size);
+    instance.lock().deallocate_range(start, size + GUARD_SIZE);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    // Note: The region starts at an odd page, so that the first allocation leaves a hole as well
+    const REGION_BASE: usize = 0x10001000;
+    const REGION_SIZE: usize = 4 * MAX_FREE_RANGE_COUNT * SMALL_PAGE_SIZE;
+
+    #[test]
+    fn fails_instead_of_losing_free_ranges() {
+        let mut allocator = KernelVirtualAllocator::new(REGION_BASE, REGION_SIZE);
+        let mut allocations = Vec::new();
+
+        // Each allocation leaves a hole before the next one because of the alignment, so every allocation adds a free range
+        while let Some(start) = allocator.allocate_range(SMALL_PAGE_SIZE, 2 * SMALL_PAGE_SIZE) {
+            allocations.push(start);
+        }
+
+        assert_eq!(allocations.len(), MAX_FREE_RANGE_COUNT - 1);
+        assert_eq!(allocator.free_count, MAX_FREE_RANGE_COUNT);
+
+        for start in allocations {
+            allocator.deallocate_range(start, SMALL_PAGE_SIZE);
+        }
+
+        // Nothing was lost, so the whole region merges back together
+        assert_eq!(allocator.free_count, 1);
+        assert_eq!(allocator.allocate_range(REGION_SIZE, SMALL_PAGE_SIZE), Some(VirtualAddress::new(REGION_BASE)));
+    }
+}
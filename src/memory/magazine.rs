@@ -0,0 +1,243 @@
+use core::{alloc::Layout, ptr};
+
+use crate::low::processor::Processor;
+
+use super::{
+    frame_database,
+    numa,
+    physical_buddy_allocator::{self, Zone, L7_SIZE},
+    physical_slab_allocator::{self, PhysicalSlabAllocator, SIZE_CLASS_COUNT},
+    PhysicalAddress, VirtualAddress
+};
+
+pub const MAGAZINE_SIZE: usize = 32;
+
+// Refills and drains move half a magazine, so that alternating allocations and frees don't touch the global allocators every time
+const BATCH_SIZE: usize = MAGAZINE_SIZE / 2;
+
+// A stack of free pages or objects of a single size
+struct Magazine {
+    objects: [*mut u8; MAGAZINE_SIZE],
+    count: usize
+}
+
+impl Magazine {
+    const fn new() -> Magazine {
+        Self { objects: [ptr::null_mut(); MAGAZINE_SIZE], count: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.count == MAGAZINE_SIZE
+    }
+
+    fn pop(&mut self) -> Option<*mut u8> {
+        if self.is_empty() {
+            return None;
+        }
+
+        self.count -= 1;
+        Some(self.objects[self.count])
+    }
+
+    fn push(&mut self, object: *mut u8) {
+        assert!(!self.is_full(), "Magazine: Pushed to a full magazine");
+
+        self.objects[self.count] = object;
+        self.count += 1;
+    }
+}
+
+// Free memory cached by a processor, so that most allocations don't take the global allocator locks
+pub struct AllocationCaches {
+    busy: bool, // Set while the caches are in use, so that reentrant allocations go to the global allocators instead
+    pages: Magazine, // L7 slabs of the buddy allocator
+    objects: [Magazine; SIZE_CLASS_COUNT] // Indexed by the size class of the slab allocator
+}
+
+impl AllocationCaches {
+    pub const fn new() -> AllocationCaches {
+        const EMPTY: Magazine = Magazine::new();
+        Self { busy: false, pages: Magazine::new(), objects: [EMPTY; SIZE_CLASS_COUNT] }
+    }
+}
+
+impl Default for AllocationCaches {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Calls the function with the caches of the current processor, returns None if the caches can't be used.
+// Todo: Preemption must be disabled here once threads can move between processors
+fn with_caches<T>(function: impl FnOnce(&mut AllocationCaches) -> T) -> Option<T> {
+    // Note: Memory is allocated before the processor is set up, in which case there are no caches
+    let caches = Processor::try_current()?.allocation_caches;
+
+    if caches.is_null() {
+        return None;
+    }
+
+    let caches = unsafe { &mut *caches };
+
+    // An interrupt handler or a reclaim callback may allocate while the caches are in use
+    if caches.busy {
+        return None;
+    }
+
+    caches.busy = true;
+    let result = function(caches);
+    caches.busy = false;
+
+    Some(result)
+}
+
+fn page_layout() -> Layout {
+    Layout::from_size_align(L7_SIZE, L7_SIZE).unwrap()
+}
+
+fn get_physical_address(page: *mut u8) -> PhysicalAddress {
+    PhysicalAddress::from(VirtualAddress::new(page as usize))
+}
+
+fn refill_pages(magazine: &mut Magazine) {
+    let node = numa::current_node();
+    let mut allocator = physical_buddy_allocator::instance.lock();
+
+    for _ in 0..BATCH_SIZE {
+        let Ok(page) = allocator.try_allocate_on_node(page_layout(), Zone::Normal, node) else {
+            break;
+        };
+
+        // Note: The buddy allocator attributes the page to the kernel heap, but it is not in use until the cache hands it out
+        frame_database::instance.lock().cached(get_physical_address(page), L7_SIZE);
+        magazine.push(page);
+    }
+}
+
+fn drain_pages(magazine: &mut Magazine, count: usize) {
+    let mut allocator = physical_buddy_allocator::instance.lock();
+
+    for _ in 0..count {
+        let Some(page) = magazine.pop() else {
+            break;
+        };
+
+        allocator.deallocate(page, page_layout());
+    }
+}
+
+// Returns None if the page must be allocated from the buddy allocator directly
+pub fn allocate_page() -> Option<*mut u8> {
+    let page = with_caches(|caches| {
+        if caches.pages.is_empty() {
+            refill_pages(&mut caches.pages);
+        }
+
+        caches.pages.pop()
+    })??;
+
+    // The pages in the magazines are owned by the cache until they are handed out
+    frame_database::instance.lock().allocated(get_physical_address(page), L7_SIZE);
+
+    Some(page)
+}
+
+// Returns false if the page must be given back to the buddy allocator directly
+pub fn deallocate_page(page: *mut u8) -> bool {
+    with_caches(|caches| {
+        // Note: The frame is checked before it is cached, so that freeing a pinned frame is still noticed right away
+        frame_database::instance.lock().cached(get_physical_address(page), L7_SIZE);
+
+        if caches.pages.is_full() {
+            drain_pages(&mut caches.pages, BATCH_SIZE);
+        }
+
+        caches.pages.push(page);
+    })
+    .is_some()
+}
+
+// Calls the function with the pages cached by the current processor
+pub fn for_each_cached_page(mut function: impl FnMut(PhysicalAddress, usize)) {
+    with_caches(|caches| {
+        for page in &caches.pages.objects[..caches.pages.count] {
+            function(get_physical_address(*page), L7_SIZE);
+        }
+    });
+}
+
+// Returns None if the object must be allocated from the slab allocator directly
+pub fn allocate_object(layout: Layout) -> Option<*mut u8> {
+    with_caches(|caches| {
+        let magazine = &mut caches.objects[PhysicalSlabAllocator::get_cache_index(layout)];
+
+        if magazine.is_empty() {
+            let mut allocator = physical_slab_allocator::instance.lock();
+
+            for _ in 0..BATCH_SIZE {
+                let object = allocator.allocate(layout);
+
+                if object.is_null() {
+                    break;
+                }
+
+                magazine.push(object);
+            }
+        }
+
+        magazine.pop()
+    })?
+}
+
+// Returns false if the object must be given back to the slab allocator directly
+pub fn deallocate_object(object: *mut u8, layout: Layout) -> bool {
+    with_caches(|caches| {
+        let magazine = &mut caches.objects[PhysicalSlabAllocator::get_cache_index(layout)];
+
+        if magazine.is_full() {
+            let mut allocator = physical_slab_allocator::instance.lock();
+
+            // Objects of the same size class are interchangeable, so any layout of the class frees them
+            for _ in 0..BATCH_SIZE {
+                unsafe { allocator.deallocate(magazine.pop().unwrap(), layout) };
+            }
+        }
+
+        magazine.push(object);
+    })
+    .is_some()
+}
+
+// Reclaim callback that gives the memory cached by this processor back to the global allocators.
+// Todo: The other processors keep their caches, because we have no way to ask them yet
+pub fn reclaim(_bytes: usize) -> usize {
+    with_caches(|caches| {
+        let mut reclaimed = 0;
+
+        // Note: We might be reclaiming while an allocator is locked, in which case its memory stays cached
+        if let Some(mut allocator) = physical_slab_allocator::instance.try_lock() {
+            for (index, magazine) in caches.objects.iter_mut().enumerate() {
+                let layout = PhysicalSlabAllocator::get_size_class_layout(index);
+
+                while let Some(object) = magazine.pop() {
+                    unsafe { allocator.deallocate(object, layout) };
+                    reclaimed += layout.size();
+                }
+            }
+        }
+
+        if let Some(mut allocator) = physical_buddy_allocator::instance.try_lock() {
+            while let Some(page) = caches.pages.pop() {
+                allocator.deallocate(page, page_layout());
+                reclaimed += L7_SIZE;
+            }
+        }
+
+        reclaimed
+    })
+    .unwrap_or(0)
+}
@@ -1,39 +1,129 @@
-use alloc::vec::Vec;
+use core::{ptr, slice, sync::atomic::{AtomicUsize, Ordering}};
 
-use super::{PhysicalAddress, VirtualAddress, paging_table::PagingFlags};
-use crate::{low::x64::kernel_paging_table, memory::{paging_table::{PagingEntryFlags, PagingTable}, PAGE_SIZE}};
-use core::mem;
+use super::{PhysicalAddress, VirtualAddress, paging_table::{self, PagingFlags, PAGE_ATTRIBUTE_TABLE}, SMALL_PAGE_SIZE};
+use crate::{
+    debug_write_line,
+    low::{
+        elf::{self, SegmentFlags, PROGRAM_HEADER_KIND_LOAD},
+        x64::{
+            enable_five_level_paging,
+            is_five_level_paging_enabled,
+            is_five_level_paging_supported,
+            kernel_paging_table,
+            read_cr0,
+            read_msr,
+            write_cr0,
+            write_cr3,
+            write_msr,
+            CR0_WRITE_PROTECT,
+            EFER_NO_EXECUTE_ENABLE,
+            MSR_EFER,
+            MSR_PAT
+        }
+    },
+    memory::{paging_table::{PagingEntryFlags, PagingTable, PAGING_TABLE_ENTRY_COUNT}, GiB, PAGE_SIZE},
+    Regions
+};
 
-const KERNEL_ENTRY_INDEX: usize = 0x100;
-const KERNEL_MAP_BASE: usize = 0xFFFF800000000000;
+pub const KERNEL_ENTRY_INDEX: usize = 0x100;
 
-pub const fn to_kernel_address(pointer: usize) -> usize {
-    KERNEL_MAP_BASE + pointer
+// The bootloader maps all the physical memory here, so the memory allocated before switching to our own
+// paging table is referenced through this window
+const BOOT_KERNEL_MAP_BASE: usize = 0xFFFF800000000000;
+const BOOT_KERNEL_MAP_SIZE: usize = 0x8000000000; // One 4-level top-level page entry (512 GiB)
+
+// With 5-level paging, the kernel map moves to the start of the higher half, which is 512 times larger
+const FIVE_LEVEL_KERNEL_MAP_BASE: usize = 0xFF00000000000000;
+
+// Number of top-level page entries reserved for the kernel map (64 TiB with 4-level paging, 32 PiB with 5-level paging)
+const KERNEL_MAP_ENTRY_COUNT: usize = 0x80;
+
+static KERNEL_MAP_BASE: AtomicUsize = AtomicUsize::new(BOOT_KERNEL_MAP_BASE);
+
+// End of the physical memory the kernel image is loaded into, which is identity mapped
+static KERNEL_IMAGE_END: AtomicUsize = AtomicUsize::new(0);
+
+const MAX_KERNEL_SEGMENT_COUNT: usize = 16;
+
+// Virtually contiguous kernel allocations are mapped here, so that they can consist of multiple physical slabs
+pub const KERNEL_VIRTUAL_REGION_BASE: usize = 0xFFFFC00000000000;
+pub const KERNEL_VIRTUAL_REGION_SIZE: usize = 0x8000000000; // One 4-level top-level page entry (512 GiB)
+
+// Kernel stacks are mapped here, so that each of them can have an unmapped guard page below it
+// Note: The interrupt entry recognizes the region by the bits above its size, so it must stay aligned to its size
+pub const KERNEL_STACK_REGION_BASE: usize = 0xFFFFC08000000000;
+pub const KERNEL_STACK_REGION_SIZE: usize = 0x8000000000; // One 4-level top-level page entry (512 GiB)
+
+// Device memory is mapped here, so that each mapping can have its own cache type
+pub const IO_REMAP_REGION_BASE: usize = 0xFFFFC10000000000;
+pub const IO_REMAP_REGION_SIZE: usize = 0x8000000000; // One 4-level top-level page entry (512 GiB)
+
+const TABLE_FLAGS: u64 = PagingEntryFlags::Writable.bits() | PagingEntryFlags::Present.bits();
+
+// Note: Switching to 5-level paging requires the top-level table to be below 4 GiB, which the kernel image is
+#[repr(C, align(4096))]
+struct TopLevelTable([u64; PAGING_TABLE_ENTRY_COUNT]);
+static mut KERNEL_TOP_LEVEL_TABLE: TopLevelTable = TopLevelTable([0; PAGING_TABLE_ENTRY_COUNT]);
+
+pub fn kernel_map_base() -> usize {
+    KERNEL_MAP_BASE.load(Ordering::Relaxed)
+}
+
+pub fn kernel_image_end() -> usize {
+    KERNEL_IMAGE_END.load(Ordering::Relaxed)
+}
+
+fn kernel_map_size() -> usize {
+    KERNEL_MAP_ENTRY_COUNT * PagingTable::get_entry_size(paging_table::level_count())
+}
+
+pub fn to_kernel_address(pointer: usize) -> usize {
+    kernel_map_base() + pointer
 }
 
 pub fn to_kernel<T>(pointer: *const T) -> *const T {
     unsafe {
-        pointer.byte_add(KERNEL_MAP_BASE) as *const T
+        pointer.byte_add(kernel_map_base()) as *const T
     }
 }
 
 pub fn to_kernel_mut<T>(pointer: *mut T) -> *mut T {
     unsafe {
-        pointer.byte_add(KERNEL_MAP_BASE) as *mut T
+        pointer.byte_add(kernel_map_base()) as *mut T
     }
 }
 
-pub const fn is_kernel_address(value: usize) -> bool {
-    (value & KERNEL_MAP_BASE) == KERNEL_MAP_BASE
+const fn is_boot_kernel_address(value: usize) -> bool {
+    value >= BOOT_KERNEL_MAP_BASE && value < BOOT_KERNEL_MAP_BASE + BOOT_KERNEL_MAP_SIZE
 }
 
-pub const fn to_physical_address(value: usize) -> usize {
-    value & !KERNEL_MAP_BASE
+// Returns whether the address is in the direct map of physical memory
+pub fn is_kernel_address(value: usize) -> bool {
+    let base = kernel_map_base();
+    (value >= base && value < base + kernel_map_size()) || is_boot_kernel_address(value)
+}
+
+// Note: This only works for the direct map and the identity map, use `translate` for anything else
+pub fn to_physical_address(value: usize) -> usize {
+    if is_boot_kernel_address(value) {
+        return value - BOOT_KERNEL_MAP_BASE;
+    }
+
+    value & !kernel_map_base()
+}
+
+// Returns the physical address the kernel virtual address is mapped to
+pub fn translate(virtual_address: VirtualAddress) -> Option<PhysicalAddress> {
+    if is_kernel_address(virtual_address.value()) {
+        return Some(PhysicalAddress::new(to_physical_address(virtual_address.value())));
+    }
+
+    kernel_paging_table().translate(virtual_address).map(|(physical_address, _)| physical_address)
 }
 
 // Todo: Refactor address types to use u64
 pub fn to_physical_address_u64(value: u64) -> u64 {
-    value & !(KERNEL_MAP_BASE as u64)
+    to_physical_address(value as usize) as u64
 }
 
 pub fn map_kernel_page_unaligned(physical_address: PhysicalAddress, flags: PagingFlags) -> VirtualAddress {
@@ -43,72 +133,215 @@ pub fn map_kernel_page_unaligned(physical_address: PhysicalAddress, flags: Pagin
     let aligned_virtual_address = virtual_address.align(PAGE_SIZE);
 
     let mut paging_table = kernel_paging_table();
-    paging_table.map_page(aligned_virtual_address, aligned_physical_address, flags);
+    // Note: The kernel map only contains data
+    paging_table.map_huge_page(aligned_virtual_address, aligned_physical_address, flags | PagingFlags::NoExecute).unwrap();
 
     virtual_address
 }
 
-pub unsafe fn switch_to_kernel_paging_table(max_available_physical_address: PhysicalAddress) {
-    const L4_SIZE: usize = 0x8000000000;
-    const L3_SIZE: usize = 0x40000000;
-    const L2_SIZE: usize = 0x200000;
+// Note: Each table is allocated separately, so that paging tables can free the tables that become empty
+fn allocate_table() -> &'static mut [u64] {
+    paging_table::allocate_table_entries()
+}
+
+fn get_table(entry: u64) -> &'static mut [u64] {
+    let physical_address = PagingTable::physical_address_from_entry(entry) as usize;
+    unsafe { slice::from_raw_parts_mut(to_kernel_address(physical_address) as *mut u64, PAGING_TABLE_ENTRY_COUNT) }
+}
+
+// Maps the physical memory from the start to the end with huge pages (2 MiB) and returns the physical address of the table.
+// Note: The level is the level of the entries in the table.
+fn map_physical_memory(level: usize, start: usize, end: usize, page_flags: u64) -> u64 {
+    let table = allocate_table();
+    let entry_size = PagingTable::get_entry_size(level);
+
+    for (index, entry) in table.iter_mut().enumerate() {
+        let address = start + index * entry_size;
 
-    // Compute how many L2, L3 and L4 we need
+        if address >= end {
+            break;
+        }
+
+        *entry = if level == 2 {
+            address as u64 | page_flags
+        } else {
+            map_physical_memory(level - 1, address, end, page_flags) | TABLE_FLAGS
+        };
+    }
+
+    to_physical_address_u64(table.as_ptr() as u64)
+}
+
+pub unsafe fn switch_to_kernel_paging_table(max_available_physical_address: PhysicalAddress, kernel_regions: &Regions) {
+    // Note: 5-level paging is used whenever the processor supports it, so that it does not go untested
+    let five_level_paging_enabled = is_five_level_paging_enabled();
+    let level_count = if five_level_paging_enabled || is_five_level_paging_supported() { 5 } else { 4 };
+    let top_level_entry_size = PagingTable::get_entry_size(level_count);
+
+    debug_write_line!("Mapper: Using {}-level paging", level_count);
+
+    // Compute how many top-level entries we need.
     // Note: We'll use huge pages (2 MiB) instead of traditional pages (4 KiB)
-    let l4_required_count = max_available_physical_address.next_multiple_of(L4_SIZE).value() / L4_SIZE;
-    let l3_required_count = max_available_physical_address.next_multiple_of(L3_SIZE).value() / L3_SIZE;
-    let l2_required_count = max_available_physical_address.next_multiple_of(L2_SIZE).value() / L2_SIZE;
-
-    // In this kernel, we assume we can cover and access all physical memory through the
-    // last top-level page entry, but if we can't do that, we should panic immediately.
-    // Note:
-    // One top-level page entry can cover up to 512 GiB. If that ever becomes a problem,
-    // we can start using the 5-level paging mechanism, where the top-level page entry can support 256 TiB.
-    assert!(l4_required_count == 1, "Top-level kernel page entry can not cover all physical memory");
-
-    const PAGE_ENTRY_COUNT_PER_LEVEL: usize = 512;
-    let l4_count = PAGE_ENTRY_COUNT_PER_LEVEL;
-    let l3_count = l3_required_count.next_multiple_of(PAGE_ENTRY_COUNT_PER_LEVEL);
-    let l2_count = l2_required_count.next_multiple_of(PAGE_ENTRY_COUNT_PER_LEVEL);
-
-    // Allocate all the entries
-    let mut entries = Vec::<u64>::with_capacity(l4_count + l3_count + l2_count);
-    let l4_base = entries.as_mut_ptr();
-    let l3_base = l4_base.add(l4_count);
-    let l2_base = l3_base.add(l3_count);
-
-    // Zero out the allocated memory
-    let entries_size = entries.capacity() * mem::size_of::<u64>();
-    core::ptr::write_bytes(entries.as_mut_ptr(), 0, entries_size);
-
-    let flags = (
-        PagingEntryFlags::PageSizeExtension |
-        PagingEntryFlags::Writable |
-        PagingEntryFlags::Present
-    ).bits();
-
-    // Identity map L4s
-    for index in 0..l4_required_count {
-        let address = to_physical_address_u64(l3_base.add(index * PAGE_ENTRY_COUNT_PER_LEVEL) as u64);
-        *l4_base.add(index) = address | flags;
+    let kernel_map_end = max_available_physical_address.next_multiple_of(PAGE_SIZE).value();
+    let kernel_map_entry_count = kernel_map_end.div_ceil(top_level_entry_size);
+
+    // The kernel map must cover all physical memory, but if it can't do that, we should panic immediately
+    assert!(kernel_map_entry_count <= KERNEL_MAP_ENTRY_COUNT, "Kernel map can not cover all physical memory");
+
+    // Note: The address spaces only share the part of the identity map that contains the kernel image
+    KERNEL_IMAGE_END.store(kernel_regions.find_end(|_| true), Ordering::Relaxed);
+
+    // Note: The kernel is loaded into low memory, so the identity map does not need more than one top-level entry
+    let identity_map_end = kernel_map_end.min(top_level_entry_size);
+
+    // Note: The page size extension bit turns an L3 entry into a 1 GiB page and is reserved in L4 and L5 entries,
+    // so it must only be set on the entries that map the huge pages.
+    let page_flags = (PagingEntryFlags::PageSizeExtension | PagingEntryFlags::Writable | PagingEntryFlags::Present).bits();
+
+    let top_level = slice::from_raw_parts_mut(
+        to_kernel_mut(ptr::addr_of_mut!(KERNEL_TOP_LEVEL_TABLE.0) as *mut u64),
+        PAGING_TABLE_ENTRY_COUNT
+    );
+    let top_level_physical_address = ptr::addr_of!(KERNEL_TOP_LEVEL_TABLE) as u64;
+
+    // Identity map the first top-level entry.
+    // Note: The kernel runs from the identity map, so it stays executable until the kernel sections are protected.
+    // The identity map and the kernel map use separate tables, so that they can be protected differently.
+    top_level[0] = map_physical_memory(level_count - 1, 0, identity_map_end, page_flags) | TABLE_FLAGS;
+
+    // Map the kernel page entries. More info of this at the assertion above.
+    for index in 0..kernel_map_entry_count {
+        let start = index * top_level_entry_size;
+        let flags = page_flags | PagingEntryFlags::NoExecute.bits();
+
+        top_level[KERNEL_ENTRY_INDEX + index] = map_physical_memory(level_count - 1, start, kernel_map_end, flags) | TABLE_FLAGS;
     }
 
-    // Identity map L3s
-    for index in 0..l3_required_count {
-        let address = to_physical_address_u64(l2_base.add(index * PAGE_ENTRY_COUNT_PER_LEVEL) as u64);
-        *l3_base.add(index) = address | flags;
+    // The kernel half is shared by all the address spaces, so its tables are created upfront.
+    // Otherwise, tables created later would only be visible in the address space that created them.
+    for entry in top_level[KERNEL_ENTRY_INDEX..].iter_mut().filter(|entry| **entry == 0) {
+        *entry = to_physical_address_u64(allocate_table().as_ptr() as u64) | TABLE_FLAGS;
     }
 
-    // Identity map L2s
-    for index in 0..l2_required_count {
-        *l2_base.add(index) = (index * PAGE_SIZE) as u64 | flags;
+    if level_count == 5 {
+        // The memory allocated so far is referenced through the boot kernel map, so it stays mapped as an alias
+        // of the first 512 GiB. Both share the lower tables, so protecting one protects the other.
+        let boot_table = get_table(top_level[PagingTable::get_entry_index(VirtualAddress::new(BOOT_KERNEL_MAP_BASE), 5)]);
+        let kernel_map_table = get_table(top_level[KERNEL_ENTRY_INDEX]);
+
+        boot_table[PagingTable::get_entry_index(VirtualAddress::new(BOOT_KERNEL_MAP_BASE), 4)] = kernel_map_table[0];
     }
 
-    // Map the kernel page entry. More info of this at the assertion above.
-    let kernel_page_entry = l4_base.add(KERNEL_ENTRY_INDEX);
-    *kernel_page_entry = to_physical_address_u64(l3_base as u64) | flags;
+    // The no-execute bit is reserved until it is enabled
+    write_msr(MSR_EFER, read_msr(MSR_EFER) | EFER_NO_EXECUTE_ENABLE);
+
+    // Make the write-combining attribute available to the pages.
+    // Note: Loading the new paging table flushes the translations cached with the old attributes.
+    write_msr(MSR_PAT, PAGE_ATTRIBUTE_TABLE);
 
     // Switch to our new paging table
-    let paging_table = PagingTable::new(Vec::leak(entries));
-    paging_table.switch();
-}
\ No newline at end of file
+    paging_table::set_level_count(level_count);
+
+    if level_count == 5 && !five_level_paging_enabled {
+        assert!(top_level_physical_address < 4 * GiB as u64, "Kernel is not loaded below 4 GiB");
+        enable_five_level_paging(top_level_physical_address);
+    } else {
+        write_cr3(top_level_physical_address);
+    }
+
+    if level_count == 5 {
+        KERNEL_MAP_BASE.store(FIVE_LEVEL_KERNEL_MAP_BASE, Ordering::Relaxed);
+    }
+
+    protect_kernel_sections(kernel_regions, identity_map_end);
+
+    // Without write protection, the kernel could still write to read-only pages
+    write_cr0(read_cr0() | CR0_WRITE_PROTECT);
+}
+
+#[derive(Clone, Copy)]
+struct KernelSegment {
+    start: usize,
+    end: usize,
+    flags: PagingFlags
+}
+
+// Maps the kernel code as read-only, the constants as read-only and non-executable and the data as non-executable.
+// Note: The kernel is identity mapped, so the virtual addresses of the segments are also their physical addresses.
+// Note: The permissions come from the ELF program headers, because the kernel regions from the loader only carry
+// their bounds. The regions are used to check that every segment lies within the memory the loader reserved for it.
+unsafe fn protect_kernel_sections(kernel_regions: &Regions, identity_map_end: usize) {
+    let mut segments = [KernelSegment { start: 0, end: 0, flags: PagingFlags::empty() }; MAX_KERNEL_SEGMENT_COUNT];
+    let mut segment_count = 0;
+
+    for header in elf::kernel_program_headers() {
+        if header.kind != PROGRAM_HEADER_KIND_LOAD || header.memory_size == 0 {
+            continue;
+        }
+
+        let segment_flags = SegmentFlags::from_bits_truncate(header.flags);
+        let start = VirtualAddress::new(header.virtual_address as usize).align(SMALL_PAGE_SIZE).value();
+        let end = VirtualAddress::new((header.virtual_address + header.memory_size) as usize)
+            .next_multiple_of(SMALL_PAGE_SIZE)
+            .value();
+
+        assert!(
+            !segment_flags.contains(SegmentFlags::Writable | SegmentFlags::Executable),
+            "Kernel segment at {:#X}-{:#X} is both writable and executable", start, end
+        );
+
+        let is_inside_kernel_regions = (0..kernel_regions.length).any(|index| {
+            let region = *kernel_regions.data.add(index);
+            region.start <= start && end <= region.end.next_multiple_of(SMALL_PAGE_SIZE)
+        });
+
+        assert!(is_inside_kernel_regions, "Kernel segment at {:#X}-{:#X} is outside the kernel regions", start, end);
+        assert!(segment_count < MAX_KERNEL_SEGMENT_COUNT, "Kernel has too many segments");
+
+        let mut flags = PagingFlags::NoFlush;
+        flags.set(PagingFlags::ReadOnly, !segment_flags.contains(SegmentFlags::Writable));
+        flags.set(PagingFlags::NoExecute, !segment_flags.contains(SegmentFlags::Executable));
+
+        // Keep the segments sorted by their start address
+        let mut index = segment_count;
+
+        while index > 0 && segments[index - 1].start > start {
+            segments[index] = segments[index - 1];
+            index -= 1;
+        }
+
+        segments[index] = KernelSegment { start, end, flags };
+        segment_count += 1;
+    }
+
+    let mut paging_table = kernel_paging_table();
+
+    // Everything else in the identity map is data
+    let data_flags = PagingFlags::NoExecute | PagingFlags::NoFlush;
+    let mut address = 0;
+
+    for segment in &segments[..segment_count] {
+        assert!(segment.start >= address, "Kernel segments share a page");
+
+        debug_write_line!(
+            "Mapper: Protecting kernel segment at {:#X}-{:#X}, writable={}, executable={}",
+            segment.start,
+            segment.end,
+            !segment.flags.contains(PagingFlags::ReadOnly),
+            !segment.flags.contains(PagingFlags::NoExecute)
+        );
+
+        let size = segment.end - segment.start;
+        paging_table.protect(VirtualAddress::new(address), segment.start - address, data_flags).unwrap();
+        paging_table.protect(VirtualAddress::new(segment.start), size, segment.flags).unwrap();
+
+        // The kernel map must not be a way around the protection
+        let kernel_address = VirtualAddress::to_kernel(PhysicalAddress::new(segment.start));
+        paging_table.protect(kernel_address, size, segment.flags | PagingFlags::NoExecute).unwrap();
+
+        address = segment.end;
+    }
+
+    paging_table.protect(VirtualAddress::new(address), identity_map_end - address, data_flags).unwrap();
+    paging_table.flush();
+}
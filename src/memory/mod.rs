@@ -1,8 +1,22 @@
+pub mod address_space;
+pub mod dma;
+pub mod frame_database;
+pub mod heap_debug;
+pub mod ioremap;
+// Note: The host test harness brings its own global allocator
+#[cfg(not(test))]
 pub mod kernel_allocator;
+pub mod kernel_stack;
+pub mod kernel_virtual_allocator;
+pub mod magazine;
 pub mod mapper;
+pub mod numa;
+pub mod page_fault;
 pub mod paging_table;
 pub mod physical_buddy_allocator;
 pub mod physical_slab_allocator;
+pub mod reclaim;
+pub mod tlb;
 
 #[allow(non_upper_case_globals)]
 pub const KiB: usize = 0x400;
@@ -30,7 +44,7 @@ impl PhysicalAddress {
         PhysicalAddress(0)
     }
 
-    pub const fn to_physical(address: VirtualAddress) -> PhysicalAddress {
+    pub fn to_physical(address: VirtualAddress) -> PhysicalAddress {
         PhysicalAddress(mapper::to_physical_address(address.value()))
     }
 
@@ -89,7 +103,7 @@ impl VirtualAddress {
         VirtualAddress(0)
     }
 
-    pub const fn to_kernel(address: PhysicalAddress) -> VirtualAddress {
+    pub fn to_kernel(address: PhysicalAddress) -> VirtualAddress {
         VirtualAddress(mapper::to_kernel_address(address.value()))
     }
 
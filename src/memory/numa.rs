@@ -0,0 +1,400 @@
+use core::{mem, slice};
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::{
+    debug_write_line,
+    interrupts::apic::{SDTHeader, RSDP20},
+    low::{processor::Processor, x64::cpuid},
+    memory::{mapper, PhysicalAddress}
+};
+
+use super::{magazine, physical_buddy_allocator};
+
+pub const MAX_NODE_COUNT: usize = 8;
+pub const MAX_NODE_RANGE_COUNT: usize = 32;
+pub const MAX_PROCESSOR_AFFINITY_COUNT: usize = 256;
+
+// Distance of a node to itself, the distances of the other nodes are relative to it
+pub const LOCAL_DISTANCE: u8 = 10;
+pub const REMOTE_DISTANCE: u8 = 20;
+
+const SRAT_PROCESSOR_AFFINITY: u8 = 0;
+const SRAT_MEMORY_AFFINITY: u8 = 1;
+const SRAT_X2APIC_PROCESSOR_AFFINITY: u8 = 2;
+const SRAT_ENABLED: u32 = 1 << 0;
+
+const CPUID_FEATURES_LEAF: u32 = 1;
+const CPUID_FEATURES_X2APIC: u32 = 1 << 21;
+const CPUID_TOPOLOGY_LEAF: u32 = 0xB;
+const CPUID_EXTENDED_TOPOLOGY_LEAF: u32 = 0x1F;
+
+#[derive(Clone, Copy)]
+pub struct NodeRange {
+    pub start: usize,
+    pub end: usize,
+    pub node: usize
+}
+
+// Which node each part of the physical memory belongs to and in which order the nodes are tried
+#[derive(Clone, Copy)]
+pub struct NodeMap {
+    ranges: [NodeRange; MAX_NODE_RANGE_COUNT],
+    range_count: usize,
+    node_count: usize,
+    order: [[u8; MAX_NODE_COUNT]; MAX_NODE_COUNT] // The nodes sorted by the distance from each node
+}
+
+impl NodeMap {
+    // All the memory belongs to a single node until the topology is known
+    pub const fn uniform() -> NodeMap {
+        Self {
+            ranges: [NodeRange { start: 0, end: 0, node: 0 }; MAX_NODE_RANGE_COUNT],
+            range_count: 0,
+            node_count: 1,
+            order: [[0; MAX_NODE_COUNT]; MAX_NODE_COUNT]
+        }
+    }
+
+    pub fn new(ranges: &[NodeRange], distances: &[[u8; MAX_NODE_COUNT]; MAX_NODE_COUNT], node_count: usize) -> NodeMap {
+        assert!(ranges.len() <= MAX_NODE_RANGE_COUNT, "NUMA: Too many memory ranges");
+        assert!(node_count > 0 && node_count <= MAX_NODE_COUNT, "NUMA: Unsupported node count");
+
+        let mut map = Self::uniform();
+        map.ranges[..ranges.len()].copy_from_slice(ranges);
+        map.range_count = ranges.len();
+        map.node_count = node_count;
+
+        for (node, (order, row)) in map.order.iter_mut().zip(distances).enumerate().take(node_count) {
+            let order = &mut order[..node_count];
+
+            for (index, other) in order.iter_mut().enumerate() {
+                *other = index as u8;
+            }
+
+            // Note: The sort is stable and the node is the closest to itself, so it stays first even if the distances are odd
+            order.sort_by_key(|other| if *other as usize == node { 0 } else { row[*other as usize] as u16 + 1 });
+        }
+
+        map
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.node_count
+    }
+
+    // Memory that no range covers belongs to the first node
+    pub fn node_of(&self, address: PhysicalAddress) -> usize {
+        self.ranges[..self.range_count]
+            .iter()
+            .find(|range| range.start <= address.value() && address.value() < range.end)
+            .map_or(0, |range| range.node)
+    }
+
+    // Returns the nodes from the closest to the farthest, starting with the node itself
+    pub fn order(&self, node: usize) -> impl Iterator<Item = usize> + '_ {
+        let node = if node < self.node_count { node } else { 0 };
+        self.order[node][..self.node_count].iter().map(|other| *other as usize)
+    }
+}
+
+#[repr(C, packed)]
+struct Srat {
+    header: SDTHeader,
+    reserved_1: u32,
+    reserved_2: u64
+}
+
+#[repr(C, packed)]
+struct SratEntryHeader {
+    kind: u8,
+    length: u8
+}
+
+#[repr(C, packed)]
+struct ProcessorAffinityEntry {
+    header: SratEntryHeader,
+    proximity_domain_low: u8,
+    apic_id: u8,
+    flags: u32,
+    sapic_eid: u8,
+    proximity_domain_high: [u8; 3],
+    clock_domain: u32
+}
+
+#[repr(C, packed)]
+struct MemoryAffinityEntry {
+    header: SratEntryHeader,
+    proximity_domain: u32,
+    reserved_1: u16,
+    base: u64,
+    length: u64,
+    reserved_2: u32,
+    flags: u32,
+    reserved_3: u64
+}
+
+#[repr(C, packed)]
+struct X2APICProcessorAffinityEntry {
+    header: SratEntryHeader,
+    reserved_1: u16,
+    proximity_domain: u32,
+    x2apic_id: u32,
+    flags: u32,
+    clock_domain: u32,
+    reserved_2: u32
+}
+
+#[repr(C, packed)]
+struct Slit {
+    header: SDTHeader,
+    locality_count: u64
+}
+
+#[derive(Clone, Copy)]
+struct ProcessorAffinity {
+    apic_id: u32,
+    node: usize
+}
+
+// The topology as read from the tables, before it is handed to the allocator
+struct Topology {
+    domains: [u32; MAX_NODE_COUNT], // Proximity domain of each node
+    node_count: usize,
+    ranges: [NodeRange; MAX_NODE_RANGE_COUNT],
+    range_count: usize,
+    processors: [ProcessorAffinity; MAX_PROCESSOR_AFFINITY_COUNT],
+    processor_count: usize
+}
+
+impl Topology {
+    fn new() -> Topology {
+        Self {
+            domains: [0; MAX_NODE_COUNT],
+            node_count: 0,
+            ranges: [NodeRange { start: 0, end: 0, node: 0 }; MAX_NODE_RANGE_COUNT],
+            range_count: 0,
+            processors: [ProcessorAffinity { apic_id: 0, node: 0 }; MAX_PROCESSOR_AFFINITY_COUNT],
+            processor_count: 0
+        }
+    }
+
+    // Proximity domains are arbitrary numbers, so they are numbered densely in the order they appear
+    fn get_node(&mut self, domain: u32) -> Option<usize> {
+        if let Some(node) = self.domains[..self.node_count].iter().position(|other| *other == domain) {
+            return Some(node);
+        }
+
+        if self.node_count == MAX_NODE_COUNT {
+            debug_write_line!("NUMA: Too many nodes, ignoring proximity domain {}", domain);
+            return None;
+        }
+
+        self.domains[self.node_count] = domain;
+        self.node_count += 1;
+
+        Some(self.node_count - 1)
+    }
+
+    fn add_processor(&mut self, apic_id: u32, domain: u32) {
+        let Some(node) = self.get_node(domain) else {
+            return;
+        };
+
+        if self.processor_count == MAX_PROCESSOR_AFFINITY_COUNT {
+            debug_write_line!("NUMA: Too many processors, ignoring APIC {}", apic_id);
+            return;
+        }
+
+        debug_write_line!("NUMA: Processor with APIC {} is on node {}", apic_id, node);
+
+        self.processors[self.processor_count] = ProcessorAffinity { apic_id, node };
+        self.processor_count += 1;
+    }
+
+    fn add_range(&mut self, start: usize, end: usize, domain: u32) {
+        let Some(node) = self.get_node(domain) else {
+            return;
+        };
+
+        if self.range_count == MAX_NODE_RANGE_COUNT {
+            debug_write_line!("NUMA: Too many memory ranges, ignoring {:#X}-{:#X}", start, end);
+            return;
+        }
+
+        debug_write_line!("NUMA: Memory {:#X}-{:#X} is on node {}", start, end, node);
+
+        self.ranges[self.range_count] = NodeRange { start, end, node };
+        self.range_count += 1;
+    }
+
+    unsafe fn process_srat(&mut self, srat: *const Srat) {
+        let end = srat.byte_add((*srat).header.length as usize) as *const u8;
+        let mut position = srat.add(1) as *const u8;
+
+        while position < end {
+            let entry = &*(position as *const SratEntryHeader);
+
+            match entry.kind {
+                SRAT_PROCESSOR_AFFINITY => {
+                    let entry = &*(position as *const ProcessorAffinityEntry);
+                    let [high_1, high_2, high_3] = entry.proximity_domain_high;
+                    let domain = u32::from_le_bytes([entry.proximity_domain_low, high_1, high_2, high_3]);
+
+                    if entry.flags & SRAT_ENABLED != 0 {
+                        self.add_processor(entry.apic_id as u32, domain);
+                    }
+                },
+                SRAT_MEMORY_AFFINITY => {
+                    let entry = &*(position as *const MemoryAffinityEntry);
+                    let (base, length) = (entry.base as usize, entry.length as usize);
+
+                    if entry.flags & SRAT_ENABLED != 0 && length != 0 {
+                        self.add_range(base, base + length, entry.proximity_domain);
+                    }
+                },
+                SRAT_X2APIC_PROCESSOR_AFFINITY => {
+                    let entry = &*(position as *const X2APICProcessorAffinityEntry);
+
+                    if entry.flags & SRAT_ENABLED != 0 {
+                        self.add_processor(entry.x2apic_id, entry.proximity_domain);
+                    }
+                },
+                _ => {
+                    debug_write_line!("NUMA: Unprocessed SRAT entry with id of {}", entry.kind);
+                }
+            }
+
+            // Note: A zero length would never advance, so the rest of the table can't be trusted
+            if entry.length == 0 {
+                debug_write_line!("NUMA: SRAT entry has zero length");
+                break;
+            }
+
+            position = position.add(entry.length as usize);
+        }
+    }
+
+    // Reads the distances between the nodes, the table is indexed by the proximity domains
+    unsafe fn process_slit(&self, slit: *const Slit, distances: &mut [[u8; MAX_NODE_COUNT]; MAX_NODE_COUNT]) {
+        let count = (*slit).locality_count as usize;
+        let matrix = slice::from_raw_parts(slit.add(1) as *const u8, count * count);
+
+        let domains = &self.domains[..self.node_count];
+
+        for (row, from_domain) in distances.iter_mut().zip(domains) {
+            for (distance, to_domain) in row.iter_mut().zip(domains) {
+                let (from_domain, to_domain) = (*from_domain as usize, *to_domain as usize);
+
+                if from_domain < count && to_domain < count {
+                    *distance = matrix[from_domain * count + to_domain];
+                }
+            }
+        }
+    }
+}
+
+struct ProcessorNodes {
+    processors: [ProcessorAffinity; MAX_PROCESSOR_AFFINITY_COUNT],
+    count: usize
+}
+
+lazy_static! {
+    static ref processor_nodes: Mutex<ProcessorNodes> = {
+        Mutex::new(ProcessorNodes {
+            processors: [ProcessorAffinity { apic_id: 0, node: 0 }; MAX_PROCESSOR_AFFINITY_COUNT],
+            count: 0
+        })
+    };
+}
+
+// Reads the node of each processor and memory range from the ACPI tables and splits the allocator into a pool per node.
+// Note: Without the tables, the memory is treated as uniform.
+pub fn initialize(rsdp_physical_address: PhysicalAddress) {
+    let rsdp = unsafe { &*mapper::to_kernel(rsdp_physical_address.value() as *const RSDP20) };
+
+    let Some(srat) = rsdp.find_table("SRAT") else {
+        debug_write_line!("NUMA: No SRAT, memory is uniform");
+        return;
+    };
+
+    let mut topology = Topology::new();
+    unsafe { topology.process_srat(srat as *const Srat) };
+
+    if topology.node_count == 0 {
+        debug_write_line!("NUMA: SRAT has no nodes, memory is uniform");
+        return;
+    }
+
+    let mut distances = [[REMOTE_DISTANCE; MAX_NODE_COUNT]; MAX_NODE_COUNT];
+
+    for (node, row) in distances.iter_mut().enumerate() {
+        row[node] = LOCAL_DISTANCE;
+    }
+
+    match rsdp.find_table("SLIT") {
+        Some(slit) => unsafe { topology.process_slit(slit as *const Slit, &mut distances) },
+        None => {
+            debug_write_line!("NUMA: No SLIT, all the other nodes are equally far");
+        }
+    }
+
+    debug_write_line!("NUMA: {} node(s)", topology.node_count);
+
+    for (node, row) in distances[..topology.node_count].iter().enumerate() {
+        debug_write_line!("NUMA: Distances from node {}: {:?}", node, &row[..topology.node_count]);
+    }
+
+    let map = NodeMap::new(&topology.ranges[..topology.range_count], &distances, topology.node_count);
+    set_node_map(map);
+
+    let mut nodes = processor_nodes.lock();
+    nodes.processors = topology.processors;
+    nodes.count = topology.processor_count;
+}
+
+fn set_node_map(map: NodeMap) {
+    physical_buddy_allocator::instance.lock().set_node_map(map);
+
+    // The magazines were filled from the first node before the nodes were known, so they are emptied to be refilled from
+    // the local node.
+    // Note: Only the processor that initializes the nodes has caches at this point
+    magazine::reclaim(usize::MAX);
+}
+
+// Returns the node of the processor with the APIC ID, processors that the SRAT does not list belong to the first node
+pub fn node_of_processor(apic_id: u32) -> usize {
+    let nodes = processor_nodes.lock();
+
+    nodes.processors[..nodes.count]
+        .iter()
+        .find(|processor| processor.apic_id == apic_id)
+        .map_or(0, |processor| processor.node)
+}
+
+// Returns the APIC ID of the processor this runs on.
+// Note: The features leaf only has the lower 8 bits of the ID, while x2APIC entries in the SRAT have all 32 bits.
+pub fn current_apic_id() -> u32 {
+    let features = cpuid(CPUID_FEATURES_LEAF, 0);
+
+    if (features.ecx & CPUID_FEATURES_X2APIC) != 0 {
+        let max_leaf = cpuid(0, 0).eax;
+
+        for leaf in [CPUID_EXTENDED_TOPOLOGY_LEAF, CPUID_TOPOLOGY_LEAF] {
+            // The leaf is only valid if the processor reports it as supported and it describes at least one level
+            if max_leaf >= leaf && cpuid(leaf, 0).ebx != 0 {
+                return cpuid(leaf, 0).edx;
+            }
+        }
+    }
+
+    features.ebx >> 24
+}
+
+// Returns the node of the processor this runs on, which allocations prefer
+pub fn current_node() -> usize {
+    Processor::try_current().map_or(0, |processor| processor.node as usize)
+}
+
+const _: () = assert!(mem::size_of::<MemoryAffinityEntry>() == 40, "Memory affinity entry has the wrong size");
@@ -0,0 +1,116 @@
+use bitflags::bitflags;
+
+use crate::{debug_write_line, low::x64::{kernel_paging_table, read_cr2, read_cr3}};
+
+use super::{address_space, kernel_stack, PhysicalAddress, VirtualAddress, paging_table::PagingFlags};
+
+pub const PAGE_FAULT_INTERRUPT_NUMBER: u64 = 14;
+
+bitflags! {
+    #[derive(Clone, Copy, Debug)]
+    pub struct PageFaultErrorCode: u64 {
+        const Present = 1 << 0; // The page was present, so the access violated its protection
+        const Write = 1 << 1;
+        const User = 1 << 2;
+        const ReservedBit = 1 << 3;
+        const InstructionFetch = 1 << 4;
+        const ProtectionKey = 1 << 5;
+        const ShadowStack = 1 << 6;
+    }
+}
+
+// Returns whether the fault was resolved, in which case the faulting instruction can be retried
+pub fn handle(error_code: u64, instruction_pointer: u64) -> bool {
+    let address = VirtualAddress::new(unsafe { read_cr2() } as usize);
+    let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+
+    if kernel_stack::is_overflow(address) {
+        report(address, error_code, instruction_pointer);
+        panic!("Kernel stack overflow at {:#X}, accessed {:#X}", instruction_pointer, address.value());
+    }
+
+    if try_populate(address, error_code) || try_copy_on_write(address, error_code) {
+        return true;
+    }
+
+    report(address, error_code, instruction_pointer);
+    false
+}
+
+fn try_populate(address: VirtualAddress, error_code: PageFaultErrorCode) -> bool {
+    // Only missing pages can be populated, everything else is a genuine fault
+    if error_code.intersects(PageFaultErrorCode::Present | PageFaultErrorCode::ReservedBit) {
+        return false;
+    }
+
+    let top_level_physical_address = PhysicalAddress::new(unsafe { read_cr3() } as usize);
+
+    let Some(area) = address_space::find_area(top_level_physical_address, address) else {
+        return false;
+    };
+
+    let is_allowed =
+        !(error_code.contains(PageFaultErrorCode::Write) && area.flags.contains(PagingFlags::ReadOnly)) &&
+        !(error_code.contains(PageFaultErrorCode::InstructionFetch) && area.flags.contains(PagingFlags::NoExecute)) &&
+        !(error_code.contains(PageFaultErrorCode::User) && !area.flags.contains(PagingFlags::User));
+
+    if !is_allowed {
+        return false;
+    }
+
+    area.populate(&mut kernel_paging_table(), address)
+}
+
+fn try_copy_on_write(address: VirtualAddress, error_code: PageFaultErrorCode) -> bool {
+    // Shared frames are mapped read-only, so writing to them causes a protection violation
+    if !error_code.contains(PageFaultErrorCode::Present | PageFaultErrorCode::Write) ||
+        error_code.intersects(PageFaultErrorCode::ReservedBit | PageFaultErrorCode::InstructionFetch) {
+        return false;
+    }
+
+    address_space::copy_on_write(&mut kernel_paging_table(), address)
+}
+
+fn report(address: VirtualAddress, error_code: PageFaultErrorCode, instruction_pointer: u64) {
+    let access = if error_code.contains(PageFaultErrorCode::InstructionFetch) {
+        "execute"
+    } else if error_code.contains(PageFaultErrorCode::Write) {
+        "write"
+    } else {
+        "read"
+    };
+
+    let mode = if error_code.contains(PageFaultErrorCode::User) { "user" } else { "kernel" };
+
+    debug_write_line!(
+        "Page fault: Invalid {} {} access to {:#X} at {:#X}", mode, access, address.value(), instruction_pointer
+    );
+    debug_write_line!("Page fault: Error code: {:?}", error_code);
+
+    let top_level_physical_address = PhysicalAddress::new(unsafe { read_cr3() } as usize);
+    debug_write_line!("Page fault: Address space: {:#X}", top_level_physical_address.value());
+
+    match kernel_paging_table().translate(address) {
+        Some((physical_address, flags)) => {
+            debug_write_line!("Page fault: Mapped to {:#X} as {:?}", physical_address.value(), flags);
+        },
+        None => {
+            debug_write_line!("Page fault: Address is not mapped");
+        }
+    }
+
+    match address_space::find_area(top_level_physical_address, address) {
+        Some(area) => {
+            debug_write_line!(
+                "Page fault: Inside {:?} area at {:#X}-{:#X} with {:?}",
+                area.kind,
+                area.start.value(),
+                area.end.value(),
+                area.flags
+            );
+        },
+        None => {
+            debug_write_line!("Page fault: Address is not inside any area");
+        }
+    }
+}
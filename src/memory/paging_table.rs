@@ -1,14 +1,23 @@
-use super::{PhysicalAddress, VirtualAddress, mapper};
+use super::{frame_database::{self, FrameOwner}, tlb, KiB, PhysicalAddress, VirtualAddress, SMALL_PAGE_SIZE, mapper};
 use crate::{debug_write_line, low::x64::write_cr3};
-use alloc::{boxed::Box, vec};
+use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error};
 use bitflags::bitflags;
-use core::slice;
+use core::{alloc::Layout, ops::Range, ptr, slice, sync::atomic::{AtomicUsize, Ordering}};
 
 pub const PAGING_TABLE_ENTRY_COUNT: usize = 512;
 pub const PAGE_ENTRY_PHYSICAL_ADDRESS_MASK: u64 = 0x7fffffffff000;
 
-extern "C" {
-    fn flush_tlb();
+// Number of paging levels, which is 5 when 5-level paging (LA57) is enabled
+static LEVEL_COUNT: AtomicUsize = AtomicUsize::new(4);
+
+pub fn level_count() -> usize {
+    LEVEL_COUNT.load(Ordering::Relaxed)
+}
+
+// Note: This must only be changed together with the paging mode of the processor
+pub fn set_level_count(count: usize) {
+    assert!(count == 4 || count == 5, "Paging table: Unsupported level count {}", count);
+    LEVEL_COUNT.store(count, Ordering::Relaxed);
 }
 
 bitflags! {
@@ -16,26 +25,150 @@ bitflags! {
         const Present = 1 << 0;
         const Writable = 1 << 1;
         const User = 1 << 2;
-        const Cached = 1 << 4;
+        const WriteThrough = 1 << 3;
+        const CacheDisable = 1 << 4;
         const PageSizeExtension = 1 << 7;
+        const PageAttributeTable = 1 << 7; // Only in L1 entries, where the page size extension bit is not used
+        const HugePageAttributeTable = 1 << 12;
+        const Owned = 1 << 9; // Ignored by the processor
+        const CopyOnWrite = 1 << 10; // Ignored by the processor
+        const Shared = 1 << 11; // Ignored by the processor, marks a table that belongs to another paging table
+        const NoExecute = 1 << 63;
     }
 }
 
 bitflags! {
+    #[derive(Clone, Copy, Debug)]
     pub struct PagingFlags: u32 {
         const NoCache = 1 << 0;
         const NoFlush = 1 << 1;
         const User = 1 << 2;
+        const ReadOnly = 1 << 3;
+        const Owned = 1 << 4; // The frame belongs to the mapping and is freed together with it
+        const NoExecute = 1 << 5;
+        const CopyOnWrite = 1 << 6; // The frame is shared and read-only until it is written to
+        const WriteCombining = 1 << 7;
+        const WriteThrough = 1 << 8;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheType {
+    Uncached,
+    WriteCombining, // Writes are buffered and may be combined, which suits memory that is only written, such as framebuffers
+    WriteThrough,
+    WriteBack
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MappingError {
+    WritableAndExecutable
+}
+
+impl PagingFlags {
+    // Checks the flags before they are applied, so that a rejected mapping leaves the paging table unchanged
+    pub fn validate(self) -> Result<(), MappingError> {
+        if !self.intersects(PagingFlags::ReadOnly | PagingFlags::NoExecute) {
+            return Err(MappingError::WritableAndExecutable);
+        }
+
+        Ok(())
+    }
+}
+
+impl CacheType {
+    pub fn flags(self) -> PagingFlags {
+        match self {
+            CacheType::Uncached => PagingFlags::NoCache,
+            CacheType::WriteCombining => PagingFlags::WriteCombining,
+            CacheType::WriteThrough => PagingFlags::WriteThrough,
+            CacheType::WriteBack => PagingFlags::empty()
+        }
+    }
+}
+
+// The cache type of a page is selected by the index formed from its PAT, PCD and PWT bits.
+// Note: The first four entries are the defaults, so that mappings made before the table is programmed keep their meaning.
+pub const PAGE_ATTRIBUTE_TABLE_INDICES: [CacheType; 8] = [
+    CacheType::WriteBack,
+    CacheType::WriteThrough,
+    CacheType::Uncached, // Uncached minus, which can be overridden by the memory type range registers
+    CacheType::Uncached,
+    CacheType::WriteCombining,
+    CacheType::WriteThrough,
+    CacheType::Uncached,
+    CacheType::Uncached
+];
+
+// Value of the IA32_PAT MSR, which corresponds to the indices above
+pub const PAGE_ATTRIBUTE_TABLE: u64 = 0x0007040100070406;
+
+// Note: The processor requires the tables to be page aligned, which a slice of entries is not on its own
+fn get_table_layout() -> Layout {
+    Layout::from_size_align(SMALL_PAGE_SIZE, SMALL_PAGE_SIZE).unwrap()
+}
+
+// Allocates the zeroed entries of a table, which must be freed with `free_table_entries`
+pub fn allocate_table_entries() -> &'static mut [u64] {
+    let layout = get_table_layout();
+
+    unsafe {
+        let entries = alloc_zeroed(layout) as *mut u64;
+
+        if entries.is_null() {
+            handle_alloc_error(layout);
+        }
+
+        slice::from_raw_parts_mut(entries, PAGING_TABLE_ENTRY_COUNT)
+    }
+}
+
+// Note: The entries must not be used afterwards
+pub fn free_table_entries(entries: &mut [u64]) {
+    unsafe {
+        dealloc(entries.as_mut_ptr() as *mut u8, get_table_layout());
+    }
+}
+
+// Tables that were removed from the hierarchy, but that the processors may still cache until the TLB is flushed.
+// Note: The tables are linked through their first entry, which never looks present, because the tables are page aligned.
+struct FreedTables {
+    first: *mut u64
+}
+
+impl FreedTables {
+    const fn new() -> FreedTables {
+        Self { first: ptr::null_mut() }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.first.is_null()
+    }
+
+    fn push(&mut self, entries: &mut [u64]) {
+        entries[0] = self.first as u64;
+        self.first = entries.as_mut_ptr();
+    }
+
+    // Note: The TLB must be flushed before
+    fn free(&mut self) {
+        while !self.first.is_null() {
+            let entries = unsafe { slice::from_raw_parts_mut(self.first, PAGING_TABLE_ENTRY_COUNT) };
+            self.first = entries[0] as *mut u64;
+
+            free_table_entries(entries);
+        }
     }
 }
 
 pub struct PagingTable<'a> {
-    entries: &'a mut [u64]
+    entries: &'a mut [u64],
+    freed_tables: FreedTables // Removed with `PagingFlags::NoFlush`, freed by the next flush through this paging table
 }
 
 impl<'a> PagingTable<'a> {
     pub fn new(entries: &'a mut [u64]) -> Self {
-        Self { entries }
+        Self { entries, freed_tables: FreedTables::new() }
     }
 
     pub fn from_physical_address(physical_address: PhysicalAddress) -> PagingTable<'a> {
@@ -64,20 +197,70 @@ impl<'a> PagingTable<'a> {
         }
     }
 
-    pub fn set_cached(entry: &mut u64, enabled: bool) {
-        if enabled {
-            *entry |= PagingEntryFlags::Cached.bits();
+    // Note: The bit that selects the attribute is in a different position in L1 entries than in huge page entries
+    pub fn set_cache_type(entry: &mut u64, cache_type: CacheType, level: usize) {
+        // Note: Uncached uses the strong variant, so that the memory type range registers can't make it cacheable
+        let index = match cache_type {
+            CacheType::WriteBack => 0,
+            CacheType::WriteThrough => 1,
+            CacheType::Uncached => 3,
+            CacheType::WriteCombining => 4
+        };
+        let attribute_table_bit = if level == 1 {
+            PagingEntryFlags::PageAttributeTable.bits()
+        } else {
+            PagingEntryFlags::HugePageAttributeTable.bits()
+        };
+
+        *entry &= !(PagingEntryFlags::WriteThrough.bits() | PagingEntryFlags::CacheDisable.bits() | attribute_table_bit);
+
+        if index & 0b001 != 0 {
+            *entry |= PagingEntryFlags::WriteThrough.bits();
+        }
+
+        if index & 0b010 != 0 {
+            *entry |= PagingEntryFlags::CacheDisable.bits();
+        }
+
+        if index & 0b100 != 0 {
+            *entry |= attribute_table_bit;
+        }
+    }
+
+    pub fn get_cache_type(entry: u64, level: usize) -> CacheType {
+        let attribute_table_bit = if level == 1 {
+            PagingEntryFlags::PageAttributeTable.bits()
         } else {
-            *entry &= !PagingEntryFlags::Cached.bits();
+            PagingEntryFlags::HugePageAttributeTable.bits()
+        };
+
+        let mut index = 0;
+
+        if entry & PagingEntryFlags::WriteThrough.bits() != 0 {
+            index |= 0b001;
+        }
+
+        if entry & PagingEntryFlags::CacheDisable.bits() != 0 {
+            index |= 0b010;
+        }
+
+        if entry & attribute_table_bit != 0 {
+            index |= 0b100;
         }
+
+        PAGE_ATTRIBUTE_TABLE_INDICES[index]
     }
 
     pub fn set_present(entry: &mut u64) {
         *entry |= PagingEntryFlags::Present.bits();
     }
 
-    pub fn set_writable(entry: &mut u64) {
-        *entry |= PagingEntryFlags::Writable.bits();
+    pub fn set_writable(entry: &mut u64, enabled: bool) {
+        if enabled {
+            *entry |= PagingEntryFlags::Writable.bits();
+        } else {
+            *entry &= !PagingEntryFlags::Writable.bits();
+        }
     }
 
     pub fn is_present(entry: u64) -> bool {
@@ -88,78 +271,533 @@ impl<'a> PagingTable<'a> {
         entry & PAGE_ENTRY_PHYSICAL_ADDRESS_MASK
     }
 
-    pub fn map_page(&mut self, virtual_address: VirtualAddress, physical_address: PhysicalAddress, flags: PagingFlags) {
+    fn get_table(entry: u64) -> PagingTable<'a> {
+        let physical_address = Self::physical_address_from_entry(entry) as usize;
+        let virtual_address = mapper::to_kernel_address(physical_address) as *mut u64;
+        let entries = unsafe { slice::from_raw_parts_mut(virtual_address, PAGING_TABLE_ENTRY_COUNT) };
+        PagingTable::new(entries)
+    }
+
+    fn is_huge(entry: u64) -> bool {
+        (entry & PagingEntryFlags::PageSizeExtension.bits()) != 0
+    }
+
+    fn is_shared(entry: u64) -> bool {
+        (entry & PagingEntryFlags::Shared.bits()) != 0
+    }
+
+    // Returns the index of the entry at the specified level that translates the virtual address.
+    // Virtual address format: ([L5 9 bits]) [L4 9 bits] [L3 9 bits] [L2 9 bits] [L1 9 bits] [Offset 12 bits]
+    pub fn get_entry_index(virtual_address: VirtualAddress, level: usize) -> usize {
+        (virtual_address.value() >> (12 + 9 * (level - 1))) & 0b111111111
+    }
+
+    // Returns how much memory a single entry at the specified level maps
+    pub const fn get_entry_size(level: usize) -> usize {
+        SMALL_PAGE_SIZE << (9 * (level - 1))
+    }
+
+    fn allocate_table(level: usize) -> PagingTable<'a> {
+        let entries = allocate_table_entries();
+        debug_write_line!("Paging table: Created a new L{} paging table at {:p}", level, entries.as_ptr());
+
+        let physical_address = PhysicalAddress::from(VirtualAddress::new(entries.as_ptr() as usize));
+        frame_database::set_owner(physical_address, SMALL_PAGE_SIZE, FrameOwner::PageTable);
+
+        PagingTable::new(entries)
+    }
+
+    fn set_table(entry: &mut u64, table: &PagingTable) {
+        // Note: The entries are accessed through the kernel mapping, but the entry must contain the physical address
+        let physical_address = PhysicalAddress::from(VirtualAddress::new(table.entries.as_ptr() as usize));
+
+        // Note: The permissions are restricted by the page entries, so the tables allow everything
+        let mut value = 0;
+        Self::set_address(&mut value, physical_address.value() as u64);
+        Self::set_writable(&mut value, true);
+        Self::set_user_accessability(&mut value, true);
+        Self::set_present(&mut value);
+
+        *entry = value;
+    }
+
+    // Replaces a huge page with a table of smaller pages that map the same memory with the same attributes
+    fn split_huge_page(entry: &mut u64, level: usize) -> PagingTable<'a> {
+        assert!(level == 2 || level == 3, "Paging table: L{} entries can not map pages", level);
+
+        let size = Self::get_entry_size(level);
+        let child_size = Self::get_entry_size(level - 1);
+
+        // Note: The address of a huge page is aligned to its size, the low bits of the mask may contain other flags
+        let physical_address = Self::physical_address_from_entry(*entry) & !(size as u64 - 1);
+        let mut flags = *entry & !PAGE_ENTRY_PHYSICAL_ADDRESS_MASK;
+
+        // L1 entries use the page size extension bit for other purposes
+        if level == 2 {
+            flags &= !PagingEntryFlags::PageSizeExtension.bits();
+        }
+
+        // The cache type bit is among the address bits, so it must be carried over separately
+        Self::set_cache_type(&mut flags, Self::get_cache_type(*entry, level), level - 1);
+
+        debug_write_line!(
+            "Paging table: Splitting a {} KiB page at {:#X} into {} KiB pages", size / KiB, physical_address, child_size / KiB
+        );
+
+        let table = Self::allocate_table(level);
+
+        for (index, child) in table.entries.iter_mut().enumerate() {
+            *child = (physical_address + (index * child_size) as u64) | flags;
+        }
+
+        // The table must be complete before it is installed, because the processor may walk it at any time
+        Self::set_table(entry, &table);
+
+        table
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| *entry == 0)
+    }
+
+    // The processors may still cache the table, so its memory is only reused after the TLB has been flushed
+    fn free_table(entry: &mut u64, freed_tables: &mut FreedTables) {
+        let table = Self::get_table(*entry);
+        debug_write_line!("Paging table: Freeing an empty paging table at {:p}", table.entries.as_ptr());
+
+        *entry = 0;
+        freed_tables.push(table.entries);
+    }
+
+    // Frees the tables removed since the last flush, the caller must have flushed the TLB
+    fn free_removed_tables(&mut self) {
+        self.freed_tables.free();
+    }
+
+    // Frees the tables on the way to the virtual address once they no longer map anything.
+    // Note: The tables referenced by the top-level entries are never freed, because the entries may be shared.
+    fn free_empty_tables(&mut self, virtual_address: VirtualAddress) {
+        let top_level = level_count();
+        let entry = self.entries[Self::get_entry_index(virtual_address, top_level)];
+
+        if !Self::is_present(entry) {
+            return;
+        }
+
+        let table = Self::get_table(entry);
+        let index = Self::get_entry_index(virtual_address, top_level - 1);
+        Self::free_empty_tables_below(&mut table.entries[index], top_level - 1, virtual_address, &mut self.freed_tables);
+    }
+
+    // Frees the table the entry points to, and the tables below it, if they no longer map anything
+    fn free_empty_tables_below(entry: &mut u64, level: usize, virtual_address: VirtualAddress, freed_tables: &mut FreedTables) {
+        if level == 1 || !Self::is_present(*entry) || Self::is_huge(*entry) || Self::is_shared(*entry) {
+            return;
+        }
+
+        let table = Self::get_table(*entry);
+        let index = Self::get_entry_index(virtual_address, level - 1);
+        Self::free_empty_tables_below(&mut table.entries[index], level - 1, virtual_address, freed_tables);
+
+        if table.is_empty() {
+            Self::free_table(entry, freed_tables);
+        }
+    }
+
+    // Returns the entry that maps the virtual address and its level, which is above L1 for huge pages
+    fn find_page_entry(&self, virtual_address: VirtualAddress) -> Option<(&'a mut u64, usize)> {
+        let top_level = level_count();
+        let mut entry = self.entries[Self::get_entry_index(virtual_address, top_level)];
+
+        for level in (1..top_level).rev() {
+            if !Self::is_present(entry) {
+                return None;
+            }
+
+            let entries = Self::get_table(entry).entries;
+            let page_entry = &mut entries[Self::get_entry_index(virtual_address, level)];
+
+            if !Self::is_present(*page_entry) {
+                return None;
+            }
+
+            if level == 1 || Self::is_huge(*page_entry) {
+                return Some((page_entry, level));
+            }
+
+            entry = *page_entry;
+        }
+
+        None
+    }
+
+    // Returns the entry at the specified level that translates the virtual address, if the tables above it exist
+    fn find_entry(&self, virtual_address: VirtualAddress, level: usize) -> Option<u64> {
+        let top_level = level_count();
+        let mut entry = self.entries[Self::get_entry_index(virtual_address, top_level)];
+
+        for table_level in (level..top_level).rev() {
+            if !Self::is_present(entry) || Self::is_huge(entry) {
+                return None;
+            }
+
+            entry = Self::get_table(entry).entries[Self::get_entry_index(virtual_address, table_level)];
+        }
+
+        Some(entry)
+    }
+
+    fn get_or_create_table(entry: &mut u64, level: usize) -> PagingTable<'a> {
+        if !Self::is_present(*entry) {
+            let table = Self::allocate_table(level);
+            Self::set_table(entry, &table);
+            return table;
+        }
+
+        // A part of the huge page needs a different mapping
+        if Self::is_huge(*entry) {
+            return Self::split_huge_page(entry, level);
+        }
+
+        Self::get_table(*entry)
+    }
+
+    // Returns the entry at the specified level that translates the virtual address, creating the tables above it
+    fn get_or_create_entry(&mut self, virtual_address: VirtualAddress, level: usize) -> &'a mut u64 {
+        let top_level = level_count();
+        let index = Self::get_entry_index(virtual_address, top_level);
+        let mut table = Self::get_or_create_table(&mut self.entries[index], top_level);
+
+        for table_level in ((level + 1)..top_level).rev() {
+            let index = Self::get_entry_index(virtual_address, table_level);
+            table = Self::get_or_create_table(&mut table.entries[index], table_level);
+        }
+
+        let entries = table.entries;
+        &mut entries[Self::get_entry_index(virtual_address, level)]
+    }
+
+    pub fn set_no_execute(entry: &mut u64, enabled: bool) {
+        if enabled {
+            *entry |= PagingEntryFlags::NoExecute.bits();
+        } else {
+            *entry &= !PagingEntryFlags::NoExecute.bits();
+        }
+    }
+
+    // Note: The flags must have been validated by the caller
+    fn set_page_flags(entry: &mut u64, flags: &PagingFlags, level: usize) {
+        Self::set_writable(entry, !flags.contains(PagingFlags::ReadOnly));
+        Self::set_no_execute(entry, flags.contains(PagingFlags::NoExecute));
+        Self::set_cache_type(entry, Self::get_flags_cache_type(flags), level);
+        Self::set_user_accessability(entry, flags.contains(PagingFlags::User));
+
+        if flags.contains(PagingFlags::CopyOnWrite) {
+            *entry |= PagingEntryFlags::CopyOnWrite.bits();
+        } else {
+            *entry &= !PagingEntryFlags::CopyOnWrite.bits();
+        }
+    }
+
+    fn get_flags_cache_type(flags: &PagingFlags) -> CacheType {
+        if flags.contains(PagingFlags::NoCache) {
+            CacheType::Uncached
+        } else if flags.contains(PagingFlags::WriteCombining) {
+            CacheType::WriteCombining
+        } else if flags.contains(PagingFlags::WriteThrough) {
+            CacheType::WriteThrough
+        } else {
+            CacheType::WriteBack
+        }
+    }
+
+    fn get_page_flags(entry: u64, level: usize) -> PagingFlags {
+        let mut flags = Self::get_cache_type(entry, level).flags();
+        flags.set(PagingFlags::ReadOnly, (entry & PagingEntryFlags::Writable.bits()) == 0);
+        flags.set(PagingFlags::User, (entry & PagingEntryFlags::User.bits()) != 0);
+        flags.set(PagingFlags::Owned, (entry & PagingEntryFlags::Owned.bits()) != 0);
+        flags.set(PagingFlags::NoExecute, (entry & PagingEntryFlags::NoExecute.bits()) != 0);
+        flags.set(PagingFlags::CopyOnWrite, (entry & PagingEntryFlags::CopyOnWrite.bits()) != 0);
+        flags
+    }
+
+    fn set_page_entry(entry: &mut u64, physical_address: PhysicalAddress, flags: &PagingFlags, level: usize) {
+        *entry = 0;
+        Self::set_address(entry, physical_address.value() as u64);
+        Self::set_page_flags(entry, flags, level);
+        Self::set_present(entry);
+
+        if flags.contains(PagingFlags::Owned) {
+            *entry |= PagingEntryFlags::Owned.bits();
+        }
+    }
+
+    // Maps a small page (4 KiB), splitting the huge page that covers it if needed
+    pub fn map_page(
+        &mut self,
+        virtual_address: VirtualAddress,
+        physical_address: PhysicalAddress,
+        flags: PagingFlags
+    ) -> Result<(), MappingError> {
+        assert!(virtual_address.is_small_page_aligned(), "Virtual address was not small page aligned");
+        assert!(physical_address.is_small_page_aligned(), "Physical address was not small page aligned");
+        flags.validate()?;
+
+        let entry = self.get_or_create_entry(virtual_address, 1);
+        Self::set_page_entry(entry, physical_address, &flags, 1);
+
+        if !flags.contains(PagingFlags::NoFlush) {
+            tlb::flush_page(virtual_address);
+        }
+
+        Ok(())
+    }
+
+    // Maps a huge page (2 MiB)
+    pub fn map_huge_page(
+        &mut self,
+        virtual_address: VirtualAddress,
+        physical_address: PhysicalAddress,
+        flags: PagingFlags
+    ) -> Result<(), MappingError> {
         assert!(virtual_address.is_page_aligned(), "Virtual address was not page aligned");
         assert!(physical_address.is_page_aligned(), "Physical address was not page aligned");
+        flags.validate()?;
 
         debug_write_line!("Paging table: Mapping {:#X} to {:#X}", virtual_address.value(), physical_address.value());
 
-        // Virtual address format: [L4 9 bits] [L3 9 bits] [L2 9 bits] [Offset 21 bits]
-        let l2_index = (virtual_address.value() >> 21) & 0b111111111;
-        let l3_index = (virtual_address.value() >> 30) & 0b111111111;
-        let l4_index = (virtual_address.value() >> 39) & 0b111111111;
+        let entry = self.get_or_create_entry(virtual_address, 2);
 
-        let entry = &mut self.entries[l4_index];
+        // The huge page replaces the small pages
+        if Self::is_present(*entry) && !Self::is_huge(*entry) {
+            Self::free_table(entry, &mut self.freed_tables);
+        }
 
-        let l4 = if Self::is_present(*entry) {
-            let physical_address = Self::physical_address_from_entry(*entry) as usize;
-            let virtual_address = mapper::to_kernel_address(physical_address) as *mut u64;
-            let entries = unsafe { slice::from_raw_parts_mut(virtual_address, PAGING_TABLE_ENTRY_COUNT) };
-            PagingTable::new(entries)
-        } else {
-            let entries_memory = vec![0u64; PAGING_TABLE_ENTRY_COUNT].into_boxed_slice();
-            let entries: &'static mut [u64] = Box::leak(entries_memory);
-            debug_write_line!("Paging table: Created a new L4 paging table at {:p}", entries.as_ptr());
+        Self::set_page_entry(entry, physical_address, &flags, 2);
+        Self::set_page_size_extension(entry, true);
 
-            Self::set_address(entry, entries.as_ptr() as u64);
-            Self::set_writable(entry);
-            Self::set_user_accessability(entry, true);
-            Self::set_present(entry);
+        // Note: Invalidating any address within the huge page invalidates the whole page
+        if !flags.contains(PagingFlags::NoFlush) {
+            tlb::flush_page(virtual_address);
+            self.free_removed_tables();
+        }
 
-            PagingTable::new(entries)
-        };
+        Ok(())
+    }
 
-        let entry = &mut l4.entries[l3_index];
+    // Removes the small page mapping and returns the physical address it was mapped to.
+    // Note: If the page is a part of a huge page, the huge page is split and the rest of it stays mapped.
+    pub fn unmap_page(&mut self, virtual_address: VirtualAddress, flags: PagingFlags) -> Option<PhysicalAddress> {
+        assert!(virtual_address.is_small_page_aligned(), "Virtual address was not small page aligned");
 
-        let l3 = if Self::is_present(*entry) {
-            let physical_address = Self::physical_address_from_entry(*entry) as usize;
-            let virtual_address = mapper::to_kernel_address(physical_address) as *mut u64;
-            let entries = unsafe { slice::from_raw_parts_mut(virtual_address, PAGING_TABLE_ENTRY_COUNT) };
-            PagingTable::new(entries)
-        } else {
-            let entries_memory = vec![0u64; PAGING_TABLE_ENTRY_COUNT].into_boxed_slice();
-            let entries: &'static mut [u64] = Box::leak(entries_memory);
-            debug_write_line!("Paging table: Created a new L3 paging table at {:p}", entries.as_ptr());
+        let entry = loop {
+            let (entry, level) = self.find_page_entry(virtual_address)?;
 
-            Self::set_address(entry, entries.as_ptr() as u64);
-            Self::set_writable(entry);
-            Self::set_user_accessability(entry, true);
-            Self::set_present(entry);
+            if level == 1 {
+                break entry;
+            }
 
-            PagingTable::new(entries)
+            Self::split_huge_page(entry, level);
         };
 
-        let entry = &mut l3.entries[l2_index];
+        let physical_address = PhysicalAddress::new(Self::physical_address_from_entry(*entry) as usize);
+        *entry = 0;
 
-        Self::set_address(entry, physical_address.value() as u64);
-        Self::set_writable(entry);
-        Self::set_cached(entry, !flags.contains(PagingFlags::NoCache));
-        Self::set_user_accessability(entry, flags.contains(PagingFlags::User));
-        Self::set_page_size_extension(entry, true);
-        Self::set_present(entry);
+        self.free_empty_tables(virtual_address);
+
+        // Note: Invalidating a page also invalidates all the cached tables, so the removed tables can be freed afterwards
+        if !flags.contains(PagingFlags::NoFlush) {
+            tlb::flush_page(virtual_address);
+            self.free_removed_tables();
+        }
+
+        Some(physical_address)
+    }
+
+    // Removes all the mappings in the range, the range may contain holes
+    pub fn unmap(&mut self, virtual_address: VirtualAddress, size: usize, flags: PagingFlags) {
+        assert!(virtual_address.is_small_page_aligned(), "Virtual address was not small page aligned");
+        assert!(size.is_multiple_of(SMALL_PAGE_SIZE), "Size was not a multiple of small page size");
+
+        let mut offset = 0;
+
+        while offset < size {
+            let address = VirtualAddress::new(virtual_address.value() + offset);
+
+            match self.find_page_entry(address) {
+                // Huge pages within the range are removed as a whole instead of being split
+                Some((entry, level)) if Self::is_within(address, level, size - offset) => {
+                    *entry = 0;
+                    self.free_empty_tables(address);
+                    offset += Self::get_entry_size(level);
+                },
+                Some(_) => {
+                    self.unmap_page(address, PagingFlags::NoFlush);
+                    offset += SMALL_PAGE_SIZE;
+                },
+                None => {
+                    offset += SMALL_PAGE_SIZE;
+                }
+            }
+        }
+
+        if !flags.contains(PagingFlags::NoFlush) {
+            self.flush_range(virtual_address, size);
+        }
+    }
+
+    // Changes the attributes of all the mappings in the range, the range may contain holes
+    pub fn protect(&mut self, virtual_address: VirtualAddress, size: usize, flags: PagingFlags) -> Result<(), MappingError> {
+        assert!(virtual_address.is_small_page_aligned(), "Virtual address was not small page aligned");
+        assert!(size.is_multiple_of(SMALL_PAGE_SIZE), "Size was not a multiple of small page size");
+        flags.validate()?;
+
+        let mut offset = 0;
+
+        while offset < size {
+            let address = VirtualAddress::new(virtual_address.value() + offset);
+
+            match self.find_page_entry(address) {
+                Some((entry, level)) if Self::is_within(address, level, size - offset) => {
+                    Self::set_page_flags(entry, &flags, level);
+                    offset += Self::get_entry_size(level);
+                },
+                // Only a part of the huge page changes, so it must be split first
+                Some((entry, level)) => {
+                    Self::split_huge_page(entry, level);
+                },
+                None => {
+                    offset += SMALL_PAGE_SIZE;
+                }
+            }
+        }
 
         if !flags.contains(PagingFlags::NoFlush) {
-            unsafe {
-                flush_tlb();
+            tlb::flush_range(virtual_address, size);
+        }
+
+        Ok(())
+    }
+
+    // Returns whether the whole page at the specified level, starting at the virtual address, fits in the remaining size
+    fn is_within(virtual_address: VirtualAddress, level: usize, remaining: usize) -> bool {
+        let size = Self::get_entry_size(level);
+        virtual_address.is_aligned(size) && size <= remaining
+    }
+
+    // Returns the physical address the virtual address is mapped to and the attributes of the mapping
+    pub fn translate(&self, virtual_address: VirtualAddress) -> Option<(PhysicalAddress, PagingFlags)> {
+        let (entry, level) = self.find_page_entry(virtual_address)?;
+
+        let size = Self::get_entry_size(level);
+        let page_address = Self::physical_address_from_entry(*entry) as usize & !(size - 1);
+        let physical_address = PhysicalAddress::new(page_address + (virtual_address.value() & (size - 1)));
+
+        Some((physical_address, Self::get_page_flags(*entry, level)))
+    }
+
+    // Calls the visitor with the virtual address, physical address, attributes and level of every page
+    // that is mapped through the top-level entries in the range
+    pub fn for_each_page<F>(&self, top_level_indices: Range<usize>, mut visitor: F)
+    where
+        F: FnMut(VirtualAddress, PhysicalAddress, PagingFlags, usize)
+    {
+        let top_level = level_count();
+
+        for index in top_level_indices {
+            let entry = self.entries[index];
+
+            if Self::is_present(entry) {
+                Self::visit_table(entry, top_level - 1, index * Self::get_entry_size(top_level), &mut visitor);
             }
         }
     }
 
+    fn visit_table<F>(entry: u64, level: usize, base: usize, visitor: &mut F)
+    where
+        F: FnMut(VirtualAddress, PhysicalAddress, PagingFlags, usize)
+    {
+        let table = Self::get_table(entry);
+
+        for (index, entry) in table.entries.iter().enumerate() {
+            if !Self::is_present(*entry) || Self::is_shared(*entry) {
+                continue;
+            }
+
+            let address = base + index * Self::get_entry_size(level);
+
+            if level == 1 || Self::is_huge(*entry) {
+                let physical_address = Self::physical_address_from_entry(*entry) as usize & !(Self::get_entry_size(level) - 1);
+                visitor(VirtualAddress::new(address), PhysicalAddress::new(physical_address), Self::get_page_flags(*entry, level), level);
+            } else {
+                Self::visit_table(*entry, level - 1, address, visitor);
+            }
+        }
+    }
+
+    // Frees all the tables below the top-level entries in the range, the mapped frames are not freed
+    pub fn free_tables(&mut self, top_level_indices: Range<usize>) {
+        let top_level = level_count();
+
+        for index in top_level_indices {
+            if Self::is_present(self.entries[index]) {
+                Self::free_table_tree(&mut self.entries[index], top_level - 1, &mut self.freed_tables);
+            }
+        }
+
+        // The tables are flushed all at once, instead of after each of them
+        if !self.freed_tables.is_empty() {
+            self.flush();
+        }
+    }
+
+    fn free_table_tree(entry: &mut u64, level: usize, freed_tables: &mut FreedTables) {
+        let table = Self::get_table(*entry);
+
+        for child in table.entries.iter_mut() {
+            if level > 1 && Self::is_present(*child) && !Self::is_huge(*child) && !Self::is_shared(*child) {
+                Self::free_table_tree(child, level - 1, freed_tables);
+            }
+        }
+
+        Self::free_table(entry, freed_tables);
+    }
+
+    // Makes the top-level entries in the range point to the same tables as the source, so that both share the mappings
+    pub fn share_entries(&mut self, source: &PagingTable, top_level_indices: Range<usize>) {
+        for index in top_level_indices {
+            self.entries[index] = source.entries[index];
+        }
+    }
+
+    // Makes the entries at the level that map the memory below the end point to the same tables as the source.
+    // Note: The tables above the level are private, so the rest of the top-level entry can be mapped separately.
+    // The shared tables are skipped when the pages are visited and when the tables are freed.
+    pub fn share_lower_entries(&mut self, source: &PagingTable, level: usize, end: usize) {
+        for address in (0..end).step_by(Self::get_entry_size(level)) {
+            let virtual_address = VirtualAddress::new(address);
+            let source_entry = source.find_entry(virtual_address, level).expect("Paging table: Shared memory is not mapped");
+
+            *self.get_or_create_entry(virtual_address, level) = source_entry | PagingEntryFlags::Shared.bits();
+        }
+    }
+
+    // Note: The tables removed without flushing are freed once the TLB has been flushed
+    pub fn flush(&mut self) {
+        tlb::flush_all();
+        self.free_removed_tables();
+    }
+
+    pub fn flush_range(&mut self, virtual_address: VirtualAddress, size: usize) {
+        tlb::flush_range(virtual_address, size);
+        self.free_removed_tables();
+    }
+
+    // Note: Writing CR3 flushes the TLB by itself
     pub fn switch(&self) {
         unsafe {
             let physical_address = PhysicalAddress::to_physical(VirtualAddress::new(self.entries.as_ptr() as usize));
             write_cr3(physical_address.value() as u64);
-            flush_tlb(); // Todo: Verify this is needed?
         }
     }
 }
@@ -1,16 +1,19 @@
-use core::{alloc::Layout, mem, ptr};
+use core::{alloc::Layout, fmt, mem, ptr};
 
 use lazy_static::lazy_static;
 use spin::Mutex;
 
-use crate::{debug_write_line, memory::MiB, Region, RegionKind, Regions};
+use crate::{debug_write_line, memory::{GiB, KiB, MiB}, Region, RegionKind, Regions};
 
-use super::{mapper, PhysicalAddress, VirtualAddress};
+use super::{
+    frame_database,
+    magazine,
+    numa::{self, NodeMap, MAX_NODE_COUNT},
+    reclaim,
+    PhysicalAddress,
+    VirtualAddress
+};
 
-// Todo: Should we just make the max memory adaptive?
-// pub const MAX_MEMORY: usize = 0x2000000000; // 128 GB
-// pub const MAX_MEMORY: usize = 0x400000000; // 16 GB
-pub const MAX_MEMORY: usize = 0x100000000; // 4 GB
 pub const LAYER_COUNT: usize = 8;
 
 pub const L0_SIZE: usize = 0x80000;
@@ -22,37 +25,110 @@ pub const L5_SIZE: usize = 0x4000;
 pub const L6_SIZE: usize = 0x2000;
 pub const L7_SIZE: usize = 0x1000;
 
-pub const L0_COUNT: usize = MAX_MEMORY / L0_SIZE;
-pub const L1_COUNT: usize = MAX_MEMORY / L1_SIZE;
-pub const L2_COUNT: usize = MAX_MEMORY / L2_SIZE;
-pub const L3_COUNT: usize = MAX_MEMORY / L3_SIZE;
-pub const L4_COUNT: usize = MAX_MEMORY / L4_SIZE;
-pub const L5_COUNT: usize = MAX_MEMORY / L5_SIZE;
-pub const L6_COUNT: usize = MAX_MEMORY / L6_SIZE;
-pub const L7_COUNT: usize = MAX_MEMORY / L7_SIZE;
+pub const ZONE_COUNT: usize = 3;
 
-pub const ALLOCATION_SIZE: usize = 
-    LAYER_COUNT * mem::size_of::<Layer>() +
-    (L0_COUNT + L1_COUNT + L2_COUNT + L3_COUNT + L4_COUNT + L5_COUNT + L6_COUNT + L7_COUNT) / 8;
+// Legacy devices can only address the first 16 MiB and 32-bit devices the first 4 GiB.
+// Note: Both ends are multiples of L0 slabs, so a slab never crosses zones.
+pub const DMA16_ZONE_END: usize = 16 * MiB;
+pub const DMA32_ZONE_END: usize = 4 * GiB;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Zone {
+    Dma16,
+    Dma32,
+    Normal
+}
+
+pub const ZONES: [Zone; ZONE_COUNT] = [Zone::Dma16, Zone::Dma32, Zone::Normal];
+
+// Each node has its own free lists for each zone
+pub const FREE_LIST_COUNT: usize = MAX_NODE_COUNT * ZONE_COUNT;
+
+impl Zone {
+    pub fn of(address: PhysicalAddress) -> Zone {
+        match address.value() {
+            address if address < DMA16_ZONE_END => Zone::Dma16,
+            address if address < DMA32_ZONE_END => Zone::Dma32,
+            _ => Zone::Normal
+        }
+    }
+
+    // Returns the zones the allocation may be placed in, in the order of preference.
+    // The lower zones are scarce, so they are only used when the upper zones run out.
+    pub fn fallbacks(self) -> &'static [Zone] {
+        match self {
+            Zone::Dma16 => &[Zone::Dma16],
+            Zone::Dma32 => &[Zone::Dma32, Zone::Dma16],
+            Zone::Normal => &[Zone::Normal, Zone::Dma32, Zone::Dma16]
+        }
+    }
+}
+
+// Translates the physical addresses the allocator manages into addresses the allocator can access.
+// The kernel uses the kernel mapping, but this allows running the allocator over any memory, such as a test arena.
+pub trait PhysicalMemory: Copy {
+    fn to_virtual(&self, address: PhysicalAddress) -> VirtualAddress;
+    fn to_physical(&self, address: VirtualAddress) -> PhysicalAddress;
+
+    // Called when slabs are handed out and given back, so that the users of the memory can be tracked
+    fn allocated(&self, _address: PhysicalAddress, _size: usize) {}
+    fn deallocated(&self, _address: PhysicalAddress, _size: usize) {}
+}
+
+#[derive(Clone, Copy)]
+pub struct KernelMemory;
+
+impl PhysicalMemory for KernelMemory {
+    fn to_virtual(&self, address: PhysicalAddress) -> VirtualAddress {
+        VirtualAddress::to_kernel(address)
+    }
+
+    fn to_physical(&self, address: VirtualAddress) -> PhysicalAddress {
+        address.into()
+    }
+
+    fn allocated(&self, address: PhysicalAddress, size: usize) {
+        frame_database::instance.lock().allocated(address, size);
+    }
+
+    fn deallocated(&self, address: PhysicalAddress, size: usize) {
+        frame_database::instance.lock().deallocated(address, size);
+    }
+}
 
 pub struct Slab {
     next: PhysicalAddress,
     previous: PhysicalAddress
 }
 
-pub struct Layer {
+// Available slabs of a single zone of a single node
+#[derive(Clone, Copy, Default)]
+pub struct FreeList {
+    pub next: Option<PhysicalAddress>,
+    pub last: Option<PhysicalAddress>,
+    pub count: usize
+}
+
+pub struct Layer<M: PhysicalMemory> {
+    pub memory: M,
     pub depth: usize,
     pub states: *mut u8, // State bitmap for all slabs in this layer
     pub size: usize,
+    pub count: usize, // Number of slabs the state bitmap covers
+    pub used_count: usize, // Number of slabs allocated directly from this layer
 
-    pub upper: *mut Layer,
-    pub lower: *mut Layer,
+    pub upper: *mut Layer<M>,
+    pub lower: *mut Layer<M>,
 
-    pub next: Option<PhysicalAddress>,
-    pub last: Option<PhysicalAddress>
+    pub nodes: NodeMap, // Note: Each layer has a copy, so that the layers can find the free list of a slab on their own
+    pub free: [FreeList; FREE_LIST_COUNT] // Indexed by the node and the zone
 }
 
-impl Layer {
+impl<M: PhysicalMemory> Layer<M> {
+    unsafe fn get_slab(&self, address: PhysicalAddress) -> *mut Slab {
+        self.memory.to_virtual(address).value() as *mut Slab
+    }
+
     unsafe fn is_split(&self, address: PhysicalAddress) -> bool {
         assert!(address.is_aligned(self.size), "Unaligned slab address");
 
@@ -114,8 +190,8 @@ impl Layer {
 
         assert!(self.is_available(slab_index), "Can not split a slab that is not available");
 
-        // Since we're splitting the slab, it must be set unavailable
-        self.remove(slab);
+        // Since we're splitting the slab, it must be set unavailable.
+        // Note: The slab is not in the available slabs, because it was either taken or it is the part we are splitting.
         self.set_unavailable(slab_index);
 
         // Compute the addresses of the two lower layer slab
@@ -129,56 +205,78 @@ impl Layer {
         lower.split(address, to)
     }
 
+    // Number of slabs in the available slab lists of all the zones
+    pub fn free_count(&self) -> usize {
+        self.free.iter().map(|list| list.count).sum()
+    }
+
+    // Note: Buddy slabs are always in the same zone and node, so a slab is put back to the list it was taken from
+    fn get_list_index(&self, address: PhysicalAddress) -> usize {
+        get_list_index(self.nodes.node_of(address), Zone::of(address))
+    }
+
     unsafe fn add(&mut self, address: PhysicalAddress) {
-        let slab = &mut *mapper::to_kernel_mut(address.value() as *mut Slab);
+        let list_index = self.get_list_index(address);
+        let last = self.free[list_index].last;
+
+        let slab = &mut *self.get_slab(address);
         slab.next = PhysicalAddress::null();
-        slab.previous = self.last.unwrap_or(PhysicalAddress::null());
+        slab.previous = last.unwrap_or(PhysicalAddress::null());
 
         // Connect the currently last slab to this new slab
-        if let Some(last) = self.last {
-            let last_slab = &mut *mapper::to_kernel_mut(last.value() as *mut Slab);
+        if let Some(last) = last {
+            let last_slab = &mut *self.get_slab(last);
             last_slab.next = address;
         }
 
+        let list = &mut self.free[list_index];
+
         // Update the next available slab if there is none
-        if self.next.is_none() {
-            self.next = Some(address);
+        if list.next.is_none() {
+            list.next = Some(address);
         }
 
         // Update the last available slab
-        self.last = Some(address);
+        list.last = Some(address);
+        list.count += 1;
     }
 
     unsafe fn remove(&mut self, address: PhysicalAddress) {
-        let slab = &mut *mapper::to_kernel_mut(address.value() as *mut Slab);
+        let slab = &mut *self.get_slab(address);
 
         let (previous, next) = (slab.previous, slab.next);
 
         // Update the previous slab to point to the next slab
         if previous != PhysicalAddress::null() {
-            let previous_slab = &mut *mapper::to_kernel_mut(previous.value() as *mut Slab);
+            let previous_slab = &mut *self.get_slab(previous);
             previous_slab.next = next;
         }
 
         // Update the next slab to point to the previous slab
         if next != PhysicalAddress::null() {
-            let next_slab = &mut *mapper::to_kernel_mut(next.value() as *mut Slab);
+            let next_slab = &mut *self.get_slab(next);
             next_slab.previous = previous;
         }
 
+        let list_index = self.get_list_index(address);
+        let list = &mut self.free[list_index];
+
         // If we're removing the currently next available slab, make the second available slab the next one
-        if Some(address) == self.next {
-            self.next = Some(next);
+        // Note: Null means there is no such slab, so the list becomes empty
+        if Some(address) == list.next {
+            list.next = if next != PhysicalAddress::null() { Some(next) } else { None };
         }
 
         // If we're removing the currently last available slab, make the second last available slab the last one
-        if Some(address) == self.last {
-            self.last = Some(previous);
+        if Some(address) == list.last {
+            list.last = if previous != PhysicalAddress::null() { Some(previous) } else { None };
         }
+
+        list.count -= 1;
     }
 
-    unsafe fn try_allocate(&mut self) -> Option<PhysicalAddress> {
-        let slab = self.try_take()?;
+    unsafe fn try_allocate(&mut self, list_index: usize) -> Option<PhysicalAddress> {
+        let slab = self.try_take(list_index)?;
         let slab_index = slab.value() / self.size;
         self.set_unavailable(slab_index);
 
@@ -206,22 +304,28 @@ impl Layer {
         *self.states.add(byte) |= 1 << bit;
     }
 
-    unsafe fn try_take(&mut self) -> Option<PhysicalAddress> {
-        let slab = self.next?;
+    unsafe fn try_take(&mut self, list_index: usize) -> Option<PhysicalAddress> {
+        let slab = self.free[list_index].next?;
 
         // Set the next slab available
-        let next = (*mapper::to_kernel(slab.value() as *const Slab)).next;
+        let next = (*self.get_slab(slab)).next;
 
         if next != PhysicalAddress::null() {
-            self.next = Some(next);
-
-            let next_slab = &mut *mapper::to_kernel_mut(next.value() as *mut Slab);
+            let next_slab = &mut *self.get_slab(next);
             next_slab.previous = PhysicalAddress::null();
+        }
+
+        let list = &mut self.free[list_index];
+
+        if next != PhysicalAddress::null() {
+            list.next = Some(next);
         } else {
-            self.next = None;
-            self.last = None;
+            list.next = None;
+            list.last = None;
         }
 
+        list.count -= 1;
+
         Some(slab)
     }
 
@@ -267,24 +371,178 @@ impl Layer {
     unsafe fn deallocate(&mut self, address: PhysicalAddress, add: bool) {
         self.unsplit(address, add)
     }
+
+    // Moves the available slabs to the lists of their nodes
+    unsafe fn set_node_map(&mut self, nodes: NodeMap) {
+        let lists = self.free;
+        self.nodes = nodes;
+        self.free = [FreeList::default(); FREE_LIST_COUNT];
+
+        for list in lists {
+            let mut next = list.next;
+
+            while let Some(address) = next {
+                // Note: Adding the slab overwrites its links, so the following slab must be read first
+                let following = (*self.get_slab(address)).next;
+                self.add(address);
+
+                next = if following != PhysicalAddress::null() { Some(following) } else { None };
+            }
+        }
+    }
+}
+
+fn get_list_index(node: usize, zone: Zone) -> usize {
+    node * ZONE_COUNT + zone as usize
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AllocationError {
+    OutOfMemory,
+    UnsupportedLayout // The layout can not be held by a single slab
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct LayerStatistics {
+    pub size: usize,
+    pub free_slabs: usize,
+    pub used_slabs: usize
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct NodeStatistics {
+    pub free_bytes: usize
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct ZoneStatistics {
+    pub total_bytes: usize,
+    pub free_bytes: usize,
+    pub used_bytes: usize,
+    pub largest_free_block: usize
+}
+
+// Note: The layout is plain data, so that the statistics can be copied as is to user tooling
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct Statistics {
+    pub layers: [LayerStatistics; LAYER_COUNT],
+    pub zones: [ZoneStatistics; ZONE_COUNT], // Indexed by the zone
+    pub nodes: [NodeStatistics; MAX_NODE_COUNT], // Indexed by the node
+    pub node_count: usize,
+    pub total_bytes: usize,
+    pub free_bytes: usize,
+    pub used_bytes: usize,
+    pub largest_free_block: usize,
+    pub fragmentation: usize // Percentage of free memory that is only available as slabs smaller than L0 slabs
+}
+
+impl Statistics {
+    pub fn print(&self) {
+        debug_write_line!("{}", self);
+    }
+}
+
+impl fmt::Display for Statistics {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(formatter, "Physical buddy allocator: Statistics")?;
+
+        for (index, layer) in self.layers.iter().enumerate() {
+            writeln!(
+                formatter,
+                "  L{} ({} KiB): free={}, used={}",
+                index,
+                layer.size / KiB,
+                layer.free_slabs,
+                layer.used_slabs
+            )?;
+        }
+
+        for (zone, statistics) in ZONES.iter().zip(self.zones.iter()) {
+            writeln!(
+                formatter,
+                "  {:?}: total={} KiB, free={} KiB, used={} KiB, largest free block={} KiB",
+                zone,
+                statistics.total_bytes / KiB,
+                statistics.free_bytes / KiB,
+                statistics.used_bytes / KiB,
+                statistics.largest_free_block / KiB
+            )?;
+        }
+
+        for (node, statistics) in self.nodes[..self.node_count].iter().enumerate() {
+            writeln!(formatter, "  Node {}: free={} KiB", node, statistics.free_bytes / KiB)?;
+        }
+
+        writeln!(formatter, "  Total: {} KiB", self.total_bytes / KiB)?;
+        writeln!(formatter, "  Free: {} KiB", self.free_bytes / KiB)?;
+        writeln!(formatter, "  Used: {} KiB", self.used_bytes / KiB)?;
+        writeln!(formatter, "  Largest free block: {} KiB", self.largest_free_block / KiB)?;
+        write!(formatter, "  Fragmentation: {}%", self.fragmentation)
+    }
 }
 
-pub struct PhysicalBuddyAllocator {
-    layers: *mut Layer
+pub struct PhysicalBuddyAllocator<M: PhysicalMemory = KernelMemory> {
+    memory: M,
+    base: PhysicalAddress,
+    layers: *mut Layer<M>,
+    max_memory: usize, // Amount of physical memory the layers cover
+    allocation_size: usize,
+    total_memory: usize, // Amount of physical memory available for allocation
+    zone_memory: [usize; ZONE_COUNT], // Amount of physical memory available for allocation in each zone
+    nodes: NodeMap
 }
 
-unsafe impl Send for PhysicalBuddyAllocator {}
+unsafe impl<M: PhysicalMemory> Send for PhysicalBuddyAllocator<M> {}
+
+impl<M: PhysicalMemory> PhysicalBuddyAllocator<M> {
+    pub fn new(memory: M) -> PhysicalBuddyAllocator<M> {
+        Self {
+            memory,
+            base: PhysicalAddress::null(),
+            layers: ptr::null_mut(),
+            max_memory: 0,
+            allocation_size: 0,
+            total_memory: 0,
+            zone_memory: [0; ZONE_COUNT],
+            nodes: NodeMap::uniform()
+        }
+    }
+
+    fn get_max_memory(max_available_physical_address: PhysicalAddress) -> usize {
+        // The layers must cover whole L0 slabs, so that every layer has an integer number of slabs
+        max_available_physical_address.next_multiple_of(L0_SIZE).value()
+    }
 
-impl PhysicalBuddyAllocator {
-    pub fn new() -> PhysicalBuddyAllocator {
-        Self { layers: ptr::null_mut() }
+    fn get_states_size(count: usize) -> usize {
+        count.div_ceil(8) // One slab takes one bit
+    }
+
+    // Returns how many bytes the allocator needs in order to cover the physical memory up to the specified address
+    pub fn get_allocation_size(max_available_physical_address: PhysicalAddress) -> usize {
+        let mut size = LAYER_COUNT * mem::size_of::<Layer<M>>();
+        let mut count = Self::get_max_memory(max_available_physical_address) / L0_SIZE;
+
+        for _ in 0..LAYER_COUNT {
+            size += Self::get_states_size(count);
+            count *= 2;
+        }
+
+        size
+    }
+
+    pub fn max_memory(&self) -> usize {
+        self.max_memory
     }
 
     // unsafe fn get_layer(&mut self, index: usize) -> &Layer {
     //     &*self.layers.add(index)
     // }
 
-    unsafe fn get_layer_mut(&mut self, index: usize) -> &mut Layer {
+    unsafe fn get_layer_mut(&mut self, index: usize) -> &mut Layer<M> {
         &mut *self.layers.add(index)
     }
 
@@ -292,14 +550,25 @@ impl PhysicalBuddyAllocator {
         let mut states = self.layers.add(LAYER_COUNT) as *mut u8;
         let mut upper = self.layers.sub(1);
         let mut lower = self.layers.add(1);
-        let mut count = L0_COUNT;
+        let mut count = self.max_memory / L0_SIZE;
         let mut size = L0_SIZE;
 
         for depth in 0..LAYER_COUNT {
             let layer = self.layers.add(depth);
-            *layer = Layer { depth, upper, lower, states, size, next: None, last: None };
-
-            states = states.add(count / 8); // One slab takes one bit
+            *layer = Layer {
+                memory: self.memory,
+                depth,
+                upper,
+                lower,
+                states,
+                size,
+                count,
+                used_count: 0,
+                nodes: self.nodes,
+                free: [FreeList::default(); FREE_LIST_COUNT]
+            };
+
+            states = states.add(Self::get_states_size(count));
             count *= 2; // When going deeper, slabs are split into two
             size /= 2; // When going deeper, slabs are split into two
             upper = upper.add(1);
@@ -324,8 +593,9 @@ impl PhysicalBuddyAllocator {
 
         // Find out in which L0 slabs the region starts and ends.
         // Because of the recursion above, the region here can't take more than two continous L0 slabs.
+        // Note: The end is exclusive, so a region ending at a slab boundary does not touch the next slab.
         let start = PhysicalAddress::new(region.start).align(L0_SIZE).value();
-        let end = PhysicalAddress::new(region.end).align(L0_SIZE).value();
+        let end = PhysicalAddress::new(region.end - 1).align(L0_SIZE).value();
 
         let layer = self.get_layer_mut(0);
         layer.set_unavailable(start / L0_SIZE);
@@ -336,16 +606,19 @@ impl PhysicalBuddyAllocator {
         for index in 0..regions.length {
             let region = *regions.data.add(index);
 
-            if region.kind != RegionKind::Available && region.end <= max_available_physical_address.value() {
-                self.reserve_region_with_largest_slabs(region);
+            // Regions above the available memory are not covered by the layers.
+            // However, a region crossing the end must still reserve the part below it.
+            if region.kind != RegionKind::Available && region.start < max_available_physical_address.value() {
+                let end = region.end.min(max_available_physical_address.value());
+                self.reserve_region_with_largest_slabs(Region::new(region.kind, region.start, end));
             }
         }
 
         // Reserve the region this allocator takes
         let region = Region::new(
             RegionKind::Reserved,
-            self.layers as usize,
-            self.layers.add(ALLOCATION_SIZE) as usize
+            self.base.value(),
+            self.base.value() + self.allocation_size
         );
 
         debug_write_line!("Physical buddy allocator: Allocator reserves {:#X}-{:#X}", region.start, region.end);
@@ -360,34 +633,56 @@ impl PhysicalBuddyAllocator {
         // If the memory ends in the middle of the last L0 slab, we don't count that as available memory,
         // because that would require processing to split it into smaller slabs as we can't provide it as a whole.
         let slabs = max_available_physical_address.align(L0_SIZE).value() / L0_SIZE;
-        let start_slab = start.value().next_multiple_of(L0_SIZE) / L0_SIZE;
+        let start_slab = start.value().div_ceil(L0_SIZE);
         let mut total = 0;
 
+        let mut zone_memory = [0; ZONE_COUNT];
+
         for slab in start_slab..slabs {
             if layer.is_available(slab) {
-                layer.add(PhysicalAddress::new(slab * L0_SIZE));
+                let address = PhysicalAddress::new(slab * L0_SIZE);
+                layer.add(address);
+                zone_memory[Zone::of(address) as usize] += L0_SIZE;
                 total += 1;
             }
         }
 
+        self.total_memory = total * L0_SIZE;
+        self.zone_memory = zone_memory;
+
+        for (zone, memory) in ZONES.iter().zip(zone_memory) {
+            debug_write_line!("Physical buddy allocator: {:?} zone has {} MiB available memory", zone, memory / MiB);
+        }
+
         debug_write_line!("Physical buddy allocator: Total of {} available L0 slabs", total);
         debug_write_line!("Physical buddy allocator: Total of {} MiB available memory", total * L0_SIZE / MiB);
     }
 
     pub fn initialize(&mut self, base: PhysicalAddress, regions: &Regions, kernel_end: PhysicalAddress) -> PhysicalAddress {
         // Note: Notice how we initialize upper layer variable below
-        assert!(base.value() >= mem::size_of::<Layer>(), "Physical base address is too small");
-        self.layers = base.value() as *mut Layer;
-
-        // Zero out all our memory
-        unsafe {
-            ptr::write_bytes(self.layers as *mut u8, 0, ALLOCATION_SIZE);
-        }
+        assert!(base.value() >= mem::size_of::<Layer<M>>(), "Physical base address is too small");
+        self.base = base;
+        self.layers = self.memory.to_virtual(base).value() as *mut Layer<M>;
 
         // Find where physical memory ends, so that we know where to stop
         let max_available_physical_address: PhysicalAddress =
             regions.find_end(|region| region.kind == RegionKind::Available).into();
 
+        // Size the layers based on the available memory, so that all of it can be used
+        self.max_memory = Self::get_max_memory(max_available_physical_address);
+        self.allocation_size = Self::get_allocation_size(max_available_physical_address);
+
+        debug_write_line!(
+            "Physical buddy allocator: Covering {} MiB of physical memory using {} KiB",
+            self.max_memory / MiB,
+            self.allocation_size / KiB
+        );
+
+        // Zero out all our memory
+        unsafe {
+            ptr::write_bytes(self.layers as *mut u8, 0, self.allocation_size);
+        }
+
         unsafe {
             self.setup_layers();
             self.reserve(regions, max_available_physical_address);
@@ -411,17 +706,37 @@ impl PhysicalBuddyAllocator {
         }
     }
 
-    unsafe fn allocate_physical_region(&mut self, layout: Layout) -> Option<PhysicalAddress> {
-        let bytes = layout.size();
+    // Returns the layer that can hold the layout.
+    // Slabs are aligned to their size, so a slab at least as large as the alignment is aligned as well.
+    pub fn get_layer_index_by_layout(layout: Layout) -> Option<usize> {
+        Self::get_layer_index_by_size(layout.size().max(layout.align()))
+    }
+
+    // Note: The nodes are tried from the closest to the farthest, so the allowed zones of a closer node are used first
+    unsafe fn allocate_physical_region(&mut self, layout: Layout, zone: Zone, node: usize) -> Option<PhysicalAddress> {
+        let nodes = self.nodes;
+
+        for node in nodes.order(node) {
+            for zone in zone.fallbacks() {
+                if let Some(address) = self.allocate_physical_region_from_list(layout, get_list_index(node, *zone)) {
+                    return Some(address);
+                }
+            }
+        }
+
+        None
+    }
 
+    unsafe fn allocate_physical_region_from_list(&mut self, layout: Layout, list_index: usize) -> Option<PhysicalAddress> {
         // Find the layer where we want to allocate the specified amount of bytes
-        let optimal_layer_index = Self::get_layer_index_by_size(bytes)?;
+        let optimal_layer_index = Self::get_layer_index_by_layout(layout)?;
 
         // Attempt allocating the memory directly from the layer
-        if let Some(address) = self.get_layer_mut(optimal_layer_index).try_allocate() {
-            debug_write_line!(
-                "Physical buddy allocator: Allocated L{} slab for {} byte(s)", optimal_layer_index, bytes
-            );
+        let optimal_layer = self.get_layer_mut(optimal_layer_index);
+
+        // Note: Nothing is printed here, because this runs for most allocations while the allocator is locked
+        if let Some(address) = optimal_layer.try_allocate(list_index) {
+            optimal_layer.used_count += 1;
             return Some(address);
         }
 
@@ -433,50 +748,790 @@ impl PhysicalBuddyAllocator {
             // Therefore, if we find such a slab, we must split it below.
             let layer = self.get_layer_mut(layer_index);
 
-            if let Some(slab) = layer.try_take() {
-                debug_write_line!("Physical buddy allocator: Splitting L{} slab for {} byte(s)", layer_index, bytes);
-                return Some(layer.split(slab, optimal_layer_index));
+            if let Some(slab) = layer.try_take(list_index) {
+                let address = layer.split(slab, optimal_layer_index);
+
+                self.get_layer_mut(optimal_layer_index).used_count += 1;
+                return Some(address);
             }
         }
 
         // If this point is reached, there is no continuous slab available that could hold
-        // the specified amount of memory. However, we can still try using multiple slabs to hold
-        // the memory since we are using virtual addresses.
-        // In addition, the size of the allocation might be problematic to determine upon deallocation.
+        // the specified amount of memory. Allocations larger than L0 slabs are stitched together
+        // from multiple slabs by the kernel virtual allocator instead.
         None
     }
 
-    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+    // Note: Without a preference, the memory of the first node is used first
+    pub fn try_allocate(&mut self, layout: Layout) -> Result<*mut u8, AllocationError> {
+        self.try_allocate_on_node(layout, Zone::Normal, 0)
+    }
+
+    // Allocates from the zone or from the zones below it, preferring the node and then the nodes closest to it
+    pub fn try_allocate_on_node(&mut self, layout: Layout, zone: Zone, node: usize) -> Result<*mut u8, AllocationError> {
+        if Self::get_layer_index_by_layout(layout).is_none() {
+            return Err(AllocationError::UnsupportedLayout);
+        }
+
         let physical_address = unsafe {
-            self.allocate_physical_region(layout)
-                .expect("Physical buddy allocator: Out of memory")
+            self.allocate_physical_region(layout, zone, node).ok_or(AllocationError::OutOfMemory)?
         };
 
-        let virtual_address = VirtualAddress::to_kernel(physical_address);
+        assert!(
+            physical_address.is_aligned(layout.align()),
+            "Physical buddy allocator: Allocated {:#X} does not satisfy alignment of {:#X}",
+            physical_address.value(),
+            layout.align()
+        );
+
+        let layer_index = Self::get_layer_index_by_layout(layout).unwrap();
+        self.memory.allocated(physical_address, L0_SIZE >> layer_index);
 
-        virtual_address.value() as *mut u8
+        let virtual_address = self.memory.to_virtual(physical_address);
+
+        Ok(virtual_address.value() as *mut u8)
+    }
+
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        self.try_allocate(layout).expect("Physical buddy allocator: Out of memory")
     }
 
-    // Todo: Do we consider the specified layout properly?
-    pub fn deallocate(&mut self, address: *mut u8, _layout: Layout) {
-        let physical_address: PhysicalAddress = VirtualAddress::new(address as usize).into();
+    // Splits the free memory into a pool per node
+    pub fn set_node_map(&mut self, nodes: NodeMap) {
+        self.nodes = nodes;
+
+        for index in 0..LAYER_COUNT {
+            unsafe { self.get_layer_mut(index).set_node_map(nodes) };
+        }
+    }
+
+    pub fn statistics(&mut self) -> Statistics {
+        let mut statistics = Statistics {
+            total_bytes: self.total_memory,
+            node_count: self.nodes.node_count(),
+            ..Default::default()
+        };
+
+        for index in 0..LAYER_COUNT {
+            let layer = unsafe { self.get_layer_mut(index) };
+
+            statistics.layers[index] = LayerStatistics {
+                size: layer.size,
+                free_slabs: layer.free_count(),
+                used_slabs: layer.used_count
+            };
+
+            statistics.free_bytes += layer.free_count() * layer.size;
+            statistics.used_bytes += layer.used_count * layer.size;
+
+            // Layers are ordered from the largest slabs to the smallest
+            if layer.free_count() > 0 && statistics.largest_free_block == 0 {
+                statistics.largest_free_block = layer.size;
+            }
+
+            for (list_index, list) in layer.free.iter().enumerate() {
+                let zone = &mut statistics.zones[list_index % ZONE_COUNT];
+                zone.free_bytes += list.count * layer.size;
+
+                if list.count > 0 && zone.largest_free_block == 0 {
+                    zone.largest_free_block = layer.size;
+                }
+
+                statistics.nodes[list_index / ZONE_COUNT].free_bytes += list.count * layer.size;
+            }
+        }
+
+        // Note: Allocations never cross zones, so the memory of a zone that is not free is used
+        for (zone, memory) in statistics.zones.iter_mut().zip(self.zone_memory) {
+            zone.total_bytes = memory;
+            zone.used_bytes = memory - zone.free_bytes;
+        }
+
+        let largest_free_bytes = statistics.layers[0].free_slabs * L0_SIZE;
+
+        if let Some(largest_free_percentage) = (largest_free_bytes * 100).checked_div(statistics.free_bytes) {
+            statistics.fragmentation = 100 - largest_free_percentage;
+        }
+
+        statistics
+    }
+
+    unsafe fn find_owner_layer_index(&mut self, physical_address: PhysicalAddress) -> Option<usize> {
+        // The deepest layer that has the slab unavailable owns it, because the upper layers only mark it as split
+        (0..LAYER_COUNT).rev().find(|index| self.get_layer_mut(*index).owns(physical_address))
+    }
+
+    pub fn deallocate(&mut self, address: *mut u8, layout: Layout) {
+        let physical_address = self.memory.to_physical(VirtualAddress::new(address as usize));
         assert!(physical_address.is_aligned(L7_SIZE), "Physical address was not aligned");
+        assert!(
+            physical_address.is_aligned(layout.align()),
+            "Physical buddy allocator: Deallocated {:#X} does not satisfy alignment of {:#X}",
+            physical_address.value(),
+            layout.align()
+        );
 
         unsafe {
-            for index in (0..LAYER_COUNT).rev() {
-                let layer = self.get_layer_mut(index);
+            let index = self.find_owner_layer_index(physical_address)
+                .expect("Physical buddy allocator: Deallocated address was not allocated");
+            let layer = self.get_layer_mut(index);
+
+            // If the address was already deallocated, the owner is a split upper slab instead of an allocated slab
+            assert!(
+                physical_address.is_aligned(layer.size) && !layer.is_split(physical_address),
+                "Physical buddy allocator: Deallocated address was not allocated"
+            );
+
+            // The slab might be larger than the layout requires, because the allocation may have shrunk in place
+            assert!(
+                layer.size >= layout.size().max(layout.align()),
+                "Physical buddy allocator: Deallocated L{} slab can not hold the specified layout", index
+            );
+
+            layer.used_count -= 1;
+            layer.deallocate(physical_address, true);
+
+            self.memory.deallocated(physical_address, L0_SIZE >> index);
+        }
+    }
+
+    // Calls the visitor with the address and size of every free slab
+    pub fn for_each_free_slab<F>(&mut self, mut visitor: F) where F: FnMut(PhysicalAddress, usize) {
+        for index in 0..LAYER_COUNT {
+            let layer = unsafe { self.get_layer_mut(index) };
+
+            for list in layer.free {
+                let mut next = list.next;
 
-                if layer.owns(physical_address) {
-                    layer.deallocate(physical_address, true);
-                    return;
+                while let Some(address) = next {
+                    visitor(address, layer.size);
+
+                    let slab = unsafe { &*layer.get_slab(address) };
+                    next = if slab.next != PhysicalAddress::null() { Some(slab.next) } else { None };
                 }
             }
         }
     }
+
+    unsafe fn can_grow_in_place(&mut self, address: PhysicalAddress, from: usize, to: usize) -> bool {
+        for layer_index in ((to + 1)..=from).rev() {
+            let size = self.get_layer_mut(layer_index).size;
+
+            // Only the left slab can grow in place, because growing the right slab would move its start
+            if !address.is_aligned(size * 2) {
+                return false;
+            }
+
+            // The buddy slab must be free as a whole, so that the two slabs merge into the upper slab
+            let buddy_slab_index = (address.value() ^ size) / size;
+
+            if !self.get_layer_mut(layer_index).is_available(buddy_slab_index) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    unsafe fn grow_in_place(&mut self, address: PhysicalAddress, from: usize, to: usize) {
+        for layer_index in ((to + 1)..=from).rev() {
+            let layer = self.get_layer_mut(layer_index);
+            let buddy_slab = PhysicalAddress::new(address.value() ^ layer.size);
+
+            // Take the buddy slab and release our slab on this layer, so that the upper layer owns the merged slab.
+            // The upper slab is already unavailable, because it was split to create our slab.
+            layer.remove(buddy_slab);
+            layer.set_available(address.value() / layer.size);
+        }
+
+        self.get_layer_mut(from).used_count -= 1;
+        self.get_layer_mut(to).used_count += 1;
+    }
+
+    unsafe fn shrink_in_place(&mut self, address: PhysicalAddress, from: usize, to: usize) {
+        for layer_index in (from + 1)..=to {
+            let layer = self.get_layer_mut(layer_index);
+            let buddy_slab = PhysicalAddress::new(address.value() ^ layer.size);
+
+            // Keep our half of the upper slab and release the other half, so that the upper slab becomes split.
+            // Note: The buddy slab can not merge, because our slab is its buddy.
+            layer.set_unavailable(address.value() / layer.size);
+            layer.add(buddy_slab);
+        }
+
+        self.get_layer_mut(from).used_count -= 1;
+        self.get_layer_mut(to).used_count += 1;
+    }
+
+    // Attempts to resize the allocation without moving it and returns whether it succeeded
+    pub fn try_resize_in_place(&mut self, address: *mut u8, new_layout: Layout) -> bool {
+        let physical_address = self.memory.to_physical(VirtualAddress::new(address as usize));
+
+        let Some(to) = Self::get_layer_index_by_layout(new_layout) else {
+            return false;
+        };
+
+        unsafe {
+            let from = self.find_owner_layer_index(physical_address)
+                .expect("Physical buddy allocator: Resized address was not allocated");
+
+            if to == from {
+                return true;
+            }
+
+            // Release the rest of the slab, so that a shrunk allocation does not hold on to memory it no longer uses.
+            // Note: The slab must also match the new layout, because the allocation is routed by its layout when it is freed.
+            // For instance, a small page would otherwise end up in the caches of a processor with the rest of the slab.
+            if to > from {
+                self.shrink_in_place(physical_address, from, to);
+
+                let size = L0_SIZE >> to;
+                self.memory.deallocated(PhysicalAddress::new(physical_address.value() + size), (L0_SIZE >> from) - size);
+
+                return true;
+            }
+
+            if !self.can_grow_in_place(physical_address, from, to) {
+                return false;
+            }
+
+            self.grow_in_place(physical_address, from, to);
+        }
+
+        self.memory.allocated(physical_address, L0_SIZE >> to);
+
+        debug_write_line!(
+            "Physical buddy allocator: Grew {:#X} in place to {} byte(s)", physical_address.value(), new_layout.size()
+        );
+
+        true
+    }
 }
 
 lazy_static! {
     pub static ref instance: Mutex<PhysicalBuddyAllocator> = {
-        Mutex::new(PhysicalBuddyAllocator::new())
+        Mutex::new(PhysicalBuddyAllocator::new(KernelMemory))
     };
-}
\ No newline at end of file
+}
+
+// Allocates from the kernel instance and attempts to reclaim memory before giving up
+pub fn try_allocate(layout: Layout) -> Result<*mut u8, AllocationError> {
+    try_allocate_in_zone(layout, Zone::Normal)
+}
+
+// Allocates from the node of the processor this runs on if possible
+pub fn try_allocate_in_zone(layout: Layout, zone: Zone) -> Result<*mut u8, AllocationError> {
+    // Small pages are served from the caches of the processor, which only hold memory that any normal allocation can use.
+    // Note: The caches are refilled from the node of the processor, so their pages are local as well.
+    if zone == Zone::Normal && is_small_page_layout(layout) {
+        if let Some(page) = magazine::allocate_page() {
+            return Ok(page);
+        }
+    }
+
+    try_allocate_on_node(layout, zone, numa::current_node())
+}
+
+// Note: Reclaiming frees memory in any zone and node, so the allocation may still fail afterwards
+pub fn try_allocate_on_node(layout: Layout, zone: Zone, node: usize) -> Result<*mut u8, AllocationError> {
+    loop {
+        // Note: The lock must be released before reclaiming, because reclaiming deallocates memory
+        let result = instance.lock().try_allocate_on_node(layout, zone, node);
+
+        match result {
+            Err(AllocationError::OutOfMemory) => {
+                if reclaim::reclaim(layout.size()) == 0 {
+                    return result;
+                }
+            },
+            _ => return result
+        }
+    }
+}
+
+fn is_small_page_layout(layout: Layout) -> bool {
+    layout.size().max(layout.align()) <= L7_SIZE
+}
+
+// Gives the memory back to the kernel instance, through the caches of the processor if possible
+pub fn deallocate(address: *mut u8, layout: Layout) {
+    if is_small_page_layout(layout) && magazine::deallocate_page(address) {
+        return;
+    }
+
+    instance.lock().deallocate(address, layout);
+}
+
+// Note: Shared with the tests of the allocators built on top of this one, so that they run on the same arena
+#[cfg(test)]
+pub(crate) mod arena {
+    use std::{slice, vec, vec::Vec};
+
+    use super::*;
+
+    // Note: The arena crosses the end of the DMA16 zone, so that both sides of it can be tested
+    pub(crate) const ARENA_SIZE: usize = 24 * MiB;
+    pub(crate) const ALLOCATOR_BASE: usize = 0x1000;
+
+    // Simulates physical memory using an ordinary buffer, so that physical address zero is the start of the buffer
+    #[derive(Clone, Copy)]
+    pub(crate) struct ArenaMemory {
+        base: usize
+    }
+
+    impl PhysicalMemory for ArenaMemory {
+        fn to_virtual(&self, address: PhysicalAddress) -> VirtualAddress {
+            assert!(address.value() < ARENA_SIZE, "Physical address is outside of the arena");
+            VirtualAddress::new(self.base + address.value())
+        }
+
+        fn to_physical(&self, address: VirtualAddress) -> PhysicalAddress {
+            PhysicalAddress::new(address.value() - self.base)
+        }
+    }
+
+    pub(crate) struct Arena {
+        _memory: Vec<u64>,
+        pub(crate) allocator: PhysicalBuddyAllocator<ArenaMemory>
+    }
+
+    impl Arena {
+        pub(crate) fn new(regions: &[Region]) -> Arena {
+            // Slabs are aligned to their size in the kernel map, which allocators on top of this one rely on
+            let mut memory = vec![0u64; (ARENA_SIZE + L0_SIZE) / 8];
+            let base = (memory.as_mut_ptr() as usize).next_multiple_of(L0_SIZE);
+            let mut allocator = PhysicalBuddyAllocator::new(ArenaMemory { base });
+
+            let regions = Regions { data: regions.as_ptr(), length: regions.len() };
+            allocator.initialize(PhysicalAddress::new(ALLOCATOR_BASE), &regions, PhysicalAddress::null());
+
+            Self { _memory: memory, allocator }
+        }
+
+        pub(crate) fn with_available_memory() -> Arena {
+            Self::new(&[
+                Region::new(RegionKind::Reserved, 0, ALLOCATOR_BASE),
+                Region::new(RegionKind::Available, ALLOCATOR_BASE, ARENA_SIZE)
+            ])
+        }
+
+        pub(crate) fn allocate(&mut self, size: usize, alignment: usize) -> Option<PhysicalAddress> {
+            self.allocate_in_zone(size, alignment, Zone::Normal)
+        }
+
+        pub(crate) fn allocate_in_zone(&mut self, size: usize, alignment: usize, zone: Zone) -> Option<PhysicalAddress> {
+            self.allocate_on_node(size, alignment, zone, 0)
+        }
+
+        pub(crate) fn allocate_on_node(&mut self, size: usize, alignment: usize, zone: Zone, node: usize) -> Option<PhysicalAddress> {
+            let layout = Layout::from_size_align(size, alignment).unwrap();
+            unsafe { self.allocator.allocate_physical_region(layout, zone, node) }
+        }
+
+        pub(crate) fn deallocate(&mut self, address: PhysicalAddress, size: usize, alignment: usize) {
+            let layout = Layout::from_size_align(size, alignment).unwrap();
+            let address = self.allocator.memory.to_virtual(address).value() as *mut u8;
+            self.allocator.deallocate(address, layout);
+        }
+
+        pub(crate) fn memory(&mut self, address: PhysicalAddress, size: usize) -> &mut [u8] {
+            let address = self.allocator.memory.to_virtual(address).value() as *mut u8;
+            unsafe { slice::from_raw_parts_mut(address, size) }
+        }
+
+        // Returns how many L0 slabs can be allocated, so that we can verify all memory merges back together
+        pub(crate) fn count_l0_slabs(&mut self) -> usize {
+            let mut slabs = Vec::new();
+
+            while let Some(slab) = self.allocate(L0_SIZE, 1) {
+                slabs.push(slab);
+            }
+
+            for slab in slabs.iter() {
+                self.deallocate(*slab, L0_SIZE, 1);
+            }
+
+            slabs.len()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use crate::memory::numa::NodeRange;
+
+    use super::{*, arena::*};
+
+    // Xorshift generator, so that the randomized tests are reproducible
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn range(&mut self, start: usize, end: usize) -> usize {
+            start + (self.next() as usize) % (end - start)
+        }
+    }
+
+    #[test]
+    fn allocates_aligned_slabs_from_every_layer() {
+        let mut arena = Arena::with_available_memory();
+        let sizes = [L0_SIZE, L1_SIZE, L2_SIZE, L3_SIZE, L4_SIZE, L5_SIZE, L6_SIZE, L7_SIZE];
+
+        for size in sizes {
+            let address = arena.allocate(size, 1).expect("Allocation failed");
+            assert!(address.is_aligned(size));
+            assert!(address.value() + size <= ARENA_SIZE);
+        }
+    }
+
+    #[test]
+    fn splitting_and_merging_restores_largest_slabs() {
+        let mut arena = Arena::with_available_memory();
+        let total = arena.count_l0_slabs();
+
+        // The first L0 slab contains the reserved memory and the allocator itself
+        assert_eq!(total, ARENA_SIZE / L0_SIZE - 1);
+
+        let address = arena.allocate(1, 1).unwrap();
+        assert_eq!(arena.count_l0_slabs(), total - 1);
+
+        arena.deallocate(address, 1, 1);
+        assert_eq!(arena.count_l0_slabs(), total);
+    }
+
+    #[test]
+    fn buddies_merge_only_when_both_are_free() {
+        let mut arena = Arena::with_available_memory();
+        let total = arena.count_l0_slabs();
+
+        let left = arena.allocate(L7_SIZE, 1).unwrap();
+        let right = arena.allocate(L7_SIZE, 1).unwrap();
+        assert_eq!(left.value() ^ L7_SIZE, right.value());
+
+        // The right slab is still allocated, so the left slab must be reused as is
+        arena.deallocate(left, L7_SIZE, 1);
+        assert_eq!(arena.allocate(L7_SIZE, 1), Some(left));
+
+        arena.deallocate(left, L7_SIZE, 1);
+        arena.deallocate(right, L7_SIZE, 1);
+        assert_eq!(arena.count_l0_slabs(), total);
+    }
+
+    #[test]
+    fn reserved_regions_are_never_allocated() {
+        let reserved_start = 4 * MiB;
+        let reserved_end = 4 * MiB + L0_SIZE + L7_SIZE; // Ends inside the following L0 slab
+
+        let mut arena = Arena::new(&[
+            Region::new(RegionKind::Reserved, 0, ALLOCATOR_BASE),
+            Region::new(RegionKind::Available, ALLOCATOR_BASE, reserved_start),
+            Region::new(RegionKind::Reserved, reserved_start, reserved_end),
+            Region::new(RegionKind::Available, reserved_end, ARENA_SIZE)
+        ]);
+
+        let mut count = 0;
+
+        while let Some(address) = arena.allocate(L7_SIZE, 1) {
+            let start = address.value();
+            assert!(start >= L0_SIZE, "Allocated memory of the allocator itself");
+            assert!(start + L7_SIZE <= reserved_start || start >= reserved_start + 2 * L0_SIZE);
+            count += 1;
+        }
+
+        assert_eq!(count, (ARENA_SIZE / L0_SIZE - 3) * (L0_SIZE / L7_SIZE));
+    }
+
+    #[test]
+    fn honors_alignment_larger_than_size() {
+        let mut arena = Arena::with_available_memory();
+
+        // Make the next free small slab unaligned, so that the alignment can not be satisfied by accident
+        arena.allocate(L7_SIZE, 1).unwrap();
+
+        let address = arena.allocate(L7_SIZE, L3_SIZE).unwrap();
+        assert!(address.is_aligned(L3_SIZE));
+
+        arena.deallocate(address, L7_SIZE, L3_SIZE);
+    }
+
+    #[test]
+    fn grows_in_place_when_buddy_is_free() {
+        let mut arena = Arena::with_available_memory();
+        let total = arena.count_l0_slabs();
+
+        let address = arena.allocate(L7_SIZE, 1).unwrap();
+        let virtual_address = arena.allocator.memory.to_virtual(address).value() as *mut u8;
+
+        assert!(arena.allocator.try_resize_in_place(virtual_address, Layout::from_size_align(L4_SIZE, 1).unwrap()));
+
+        // Nothing else may be placed inside the grown allocation
+        let other = arena.allocate(L7_SIZE, 1).unwrap();
+        assert!(other.value() >= address.value() + L4_SIZE);
+
+        arena.deallocate(other, L7_SIZE, 1);
+        arena.deallocate(address, L4_SIZE, 1);
+        assert_eq!(arena.count_l0_slabs(), total);
+    }
+
+    #[test]
+    fn shrinking_in_place_releases_the_rest() {
+        let mut arena = Arena::with_available_memory();
+        let total = arena.count_l0_slabs();
+
+        let address = arena.allocate(L4_SIZE, 1).unwrap();
+        let virtual_address = arena.allocator.memory.to_virtual(address).value() as *mut u8;
+
+        assert!(arena.allocator.try_resize_in_place(virtual_address, Layout::from_size_align(L7_SIZE, 1).unwrap()));
+        assert_eq!(unsafe { arena.allocator.find_owner_layer_index(address) }, Some(7));
+
+        // The released part can be allocated again
+        let other = arena.allocate(L7_SIZE, 1).unwrap();
+        assert_eq!(other.value(), address.value() + L7_SIZE);
+
+        arena.deallocate(other, L7_SIZE, 1);
+        arena.deallocate(address, L7_SIZE, 1);
+        assert_eq!(arena.count_l0_slabs(), total);
+    }
+
+    #[test]
+    fn reports_allocation_errors() {
+        let mut arena = Arena::with_available_memory();
+
+        let layout = Layout::from_size_align(L0_SIZE * 2, 1).unwrap();
+        assert_eq!(arena.allocator.try_allocate(layout), Err(AllocationError::UnsupportedLayout));
+
+        let layout = Layout::from_size_align(L0_SIZE, 1).unwrap();
+
+        while arena.allocator.try_allocate(layout).is_ok() {}
+
+        assert_eq!(arena.allocator.try_allocate(layout), Err(AllocationError::OutOfMemory));
+    }
+
+    #[test]
+    fn statistics_track_free_and_used_slabs() {
+        let mut arena = Arena::with_available_memory();
+        let total = arena.count_l0_slabs();
+
+        let statistics = arena.allocator.statistics();
+        assert_eq!(statistics.total_bytes, total * L0_SIZE);
+        assert_eq!(statistics.free_bytes, total * L0_SIZE);
+        assert_eq!(statistics.largest_free_block, L0_SIZE);
+        assert_eq!(statistics.fragmentation, 0);
+
+        let address = arena.allocate(L7_SIZE, 1).unwrap();
+
+        // Splitting an L0 slab leaves one free buddy slab on each of the lower layers
+        let statistics = arena.allocator.statistics();
+        assert_eq!(statistics.layers[0].free_slabs, total - 1);
+        assert!(statistics.layers[1..].iter().all(|layer| layer.free_slabs == 1));
+        assert_eq!(statistics.layers[7].used_slabs, 1);
+        assert_eq!(statistics.used_bytes, L7_SIZE);
+        assert!(statistics.fragmentation > 0);
+
+        arena.deallocate(address, L7_SIZE, 1);
+
+        let statistics = arena.allocator.statistics();
+        assert_eq!(statistics.used_bytes, 0);
+        assert_eq!(statistics.fragmentation, 0);
+    }
+
+    #[test]
+    fn allocations_prefer_the_highest_allowed_zone() {
+        let mut arena = Arena::with_available_memory();
+
+        let address = arena.allocate_in_zone(L7_SIZE, 1, Zone::Dma16).unwrap();
+        assert!(address.value() < DMA16_ZONE_END);
+
+        // The arena has no normal memory, so normal allocations fall back to the DMA32 zone
+        for zone in [Zone::Dma32, Zone::Normal] {
+            let address = arena.allocate_in_zone(L7_SIZE, 1, zone).unwrap();
+            assert!(address.value() >= DMA16_ZONE_END);
+        }
+    }
+
+    #[test]
+    fn zones_fall_back_only_to_lower_zones() {
+        let mut arena = Arena::with_available_memory();
+        let total = arena.count_l0_slabs();
+        let mut slabs = Vec::new();
+
+        // The DMA32 allocations use up the DMA16 zone once the DMA32 zone is full
+        while let Some(slab) = arena.allocate_in_zone(L0_SIZE, 1, Zone::Dma32) {
+            slabs.push(slab);
+        }
+
+        assert_eq!(slabs.len(), total);
+        assert_eq!(arena.allocate_in_zone(L7_SIZE, 1, Zone::Dma16), None);
+
+        // Freeing memory above the DMA16 zone does not help DMA16 allocations
+        let upper = *slabs.iter().find(|slab| slab.value() >= DMA16_ZONE_END).unwrap();
+        arena.deallocate(upper, L0_SIZE, 1);
+        assert_eq!(arena.allocate_in_zone(L7_SIZE, 1, Zone::Dma16), None);
+
+        let lower = *slabs.iter().find(|slab| slab.value() < DMA16_ZONE_END).unwrap();
+        arena.deallocate(lower, L0_SIZE, 1);
+        assert_eq!(arena.allocate_in_zone(L0_SIZE, 1, Zone::Dma16), Some(lower));
+    }
+
+    // Splits the arena in half, so that the upper half belongs to the second node
+    fn set_two_nodes(arena: &mut Arena) {
+        let ranges = [
+            NodeRange { start: 0, end: ARENA_SIZE / 2, node: 0 },
+            NodeRange { start: ARENA_SIZE / 2, end: ARENA_SIZE, node: 1 }
+        ];
+
+        let mut distances = [[numa::REMOTE_DISTANCE; MAX_NODE_COUNT]; MAX_NODE_COUNT];
+        distances[0][0] = numa::LOCAL_DISTANCE;
+        distances[1][1] = numa::LOCAL_DISTANCE;
+
+        arena.allocator.set_node_map(NodeMap::new(&ranges, &distances, 2));
+    }
+
+    #[test]
+    fn allocations_prefer_the_node() {
+        let mut arena = Arena::with_available_memory();
+        let total = arena.count_l0_slabs();
+        set_two_nodes(&mut arena);
+
+        // Splitting the memory into nodes does not lose any of it
+        assert_eq!(arena.count_l0_slabs(), total);
+
+        for node in [0, 1] {
+            let address = arena.allocate_on_node(L7_SIZE, 1, Zone::Dma32, node).unwrap();
+            assert_eq!(address.value() >= ARENA_SIZE / 2, node == 1);
+        }
+    }
+
+    #[test]
+    fn nodes_fall_back_to_other_nodes() {
+        let mut arena = Arena::with_available_memory();
+        set_two_nodes(&mut arena);
+
+        let mut slabs = Vec::new();
+
+        while let Some(slab) = arena.allocate_on_node(L0_SIZE, 1, Zone::Dma32, 1) {
+            slabs.push(slab);
+        }
+
+        // The memory of the second node is used up first
+        let local_count = (ARENA_SIZE / 2) / L0_SIZE;
+        assert!(slabs[..local_count].iter().all(|slab| slab.value() >= ARENA_SIZE / 2));
+        assert!(slabs[local_count..].iter().all(|slab| slab.value() < ARENA_SIZE / 2));
+
+        let statistics = arena.allocator.statistics();
+        assert_eq!(statistics.node_count, 2);
+        assert_eq!(statistics.nodes[0].free_bytes, 0);
+        assert_eq!(statistics.nodes[1].free_bytes, 0);
+
+        arena.deallocate(slabs[0], L0_SIZE, 1);
+        assert_eq!(arena.allocator.statistics().nodes[1].free_bytes, L0_SIZE);
+    }
+
+    #[test]
+    fn statistics_track_zones() {
+        let mut arena = Arena::with_available_memory();
+        let statistics = arena.allocator.statistics();
+
+        // The first L0 slab contains the reserved memory and the allocator itself
+        let dma16 = statistics.zones[Zone::Dma16 as usize];
+        let dma32 = statistics.zones[Zone::Dma32 as usize];
+        assert_eq!(dma16.total_bytes, DMA16_ZONE_END - L0_SIZE);
+        assert_eq!(dma32.total_bytes, ARENA_SIZE - DMA16_ZONE_END);
+        assert_eq!(statistics.zones[Zone::Normal as usize].total_bytes, 0);
+
+        let address = arena.allocate_in_zone(L7_SIZE, 1, Zone::Dma16).unwrap();
+
+        let statistics = arena.allocator.statistics();
+        assert_eq!(statistics.zones[Zone::Dma16 as usize].used_bytes, L7_SIZE);
+        assert_eq!(statistics.zones[Zone::Dma32 as usize].used_bytes, 0);
+        assert_eq!(statistics.zones[Zone::Dma32 as usize].free_bytes, dma32.total_bytes);
+
+        arena.deallocate(address, L7_SIZE, 1);
+        assert_eq!(arena.allocator.statistics().zones[Zone::Dma16 as usize].free_bytes, dma16.total_bytes);
+    }
+
+    #[test]
+    #[should_panic(expected = "was not allocated")]
+    fn detects_double_free_of_merged_slab() {
+        let mut arena = Arena::with_available_memory();
+        let address = arena.allocate(L7_SIZE, 1).unwrap();
+
+        arena.deallocate(address, L7_SIZE, 1);
+        arena.deallocate(address, L7_SIZE, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "was not allocated")]
+    fn detects_double_free_next_to_allocated_buddy() {
+        let mut arena = Arena::with_available_memory();
+        let left = arena.allocate(L7_SIZE, 1).unwrap();
+        let _right = arena.allocate(L7_SIZE, 1).unwrap();
+
+        arena.deallocate(left, L7_SIZE, 1);
+        arena.deallocate(left, L7_SIZE, 1);
+    }
+
+    #[test]
+    fn randomized_allocations_never_overlap() {
+        for seed in [0x1234, 0xdeadbeef, 0xc0ffee, 0x5eed] {
+            let mut arena = Arena::with_available_memory();
+            let total = arena.count_l0_slabs();
+            let mut random = Random(seed);
+
+            // Allocations as (address, size, alignment, tag)
+            let mut allocations: Vec<(PhysicalAddress, usize, usize, u8)> = Vec::new();
+
+            for step in 0..4000 {
+                if allocations.is_empty() || random.range(0, 100) < 60 {
+                    let size = random.range(1, L0_SIZE / 4);
+                    let alignment = 1 << random.range(0, 17);
+
+                    let Some(address) = arena.allocate(size, alignment) else {
+                        continue;
+                    };
+
+                    assert!(address.is_aligned(alignment), "Seed {:#X}: Misaligned allocation", seed);
+
+                    for (other, other_size, _, _) in allocations.iter() {
+                        let overlaps = address.value() < other.value() + other_size &&
+                            other.value() < address.value() + size;
+                        assert!(!overlaps, "Seed {:#X}: Allocations overlap", seed);
+                    }
+
+                    // Fill the allocation, so that we notice if the allocator writes into allocated memory
+                    let tag = step as u8;
+                    arena.memory(address, size).fill(tag);
+                    allocations.push((address, size, alignment, tag));
+                } else {
+                    let index = random.range(0, allocations.len());
+                    let (address, size, alignment, tag) = allocations.swap_remove(index);
+
+                    assert!(
+                        arena.memory(address, size).iter().all(|value| *value == tag),
+                        "Seed {:#X}: Allocated memory was corrupted", seed
+                    );
+
+                    arena.deallocate(address, size, alignment);
+                }
+
+                // Every byte is either in a free slab or in an allocated slab
+                let statistics = arena.allocator.statistics();
+                assert_eq!(statistics.free_bytes + statistics.used_bytes, statistics.total_bytes);
+            }
+
+            for (address, size, alignment, _) in allocations {
+                arena.deallocate(address, size, alignment);
+            }
+
+            assert_eq!(arena.count_l0_slabs(), total, "Seed {:#X}: Memory did not merge back together", seed);
+        }
+    }
+}
@@ -0,0 +1,410 @@
+use core::{alloc::Layout, mem, ptr};
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::debug_write_line;
+
+use super::{
+    physical_buddy_allocator::{self, PhysicalBuddyAllocator, PhysicalMemory},
+    KiB
+};
+
+pub const SIZE_CLASS_COUNT: usize = 8;
+pub const MIN_OBJECT_SIZE: usize = 16;
+pub const MAX_OBJECT_SIZE: usize = 2 * KiB;
+
+// Objects are placed after the slab header, so the header size determines the largest alignment we can guarantee
+pub const SLAB_HEADER_SIZE: usize = 64;
+pub const MAX_OBJECT_ALIGNMENT: usize = SLAB_HEADER_SIZE;
+
+// Small objects are served from 4 KiB slabs (L7), but larger objects would waste most of such a slab
+const SMALL_SLAB_SIZE: usize = 4 * KiB;
+const LARGE_SLAB_SIZE: usize = 16 * KiB;
+const LARGE_OBJECT_SIZE: usize = KiB;
+
+// Where the caches get their slabs from, so that the caches can be tested on top of a buddy allocator of their own
+pub trait SlabSource {
+    // Returns null if there is no memory
+    fn allocate_slab(&mut self, layout: Layout) -> *mut u8;
+    fn deallocate_slab(&mut self, slab: *mut u8, layout: Layout);
+}
+
+// Takes the slabs from the kernel's buddy allocator, which reclaims memory before failing
+pub struct KernelSlabSource;
+
+impl SlabSource for KernelSlabSource {
+    fn allocate_slab(&mut self, layout: Layout) -> *mut u8 {
+        physical_buddy_allocator::try_allocate(layout).unwrap_or(ptr::null_mut())
+    }
+
+    fn deallocate_slab(&mut self, slab: *mut u8, layout: Layout) {
+        physical_buddy_allocator::deallocate(slab, layout);
+    }
+}
+
+impl<M: PhysicalMemory> SlabSource for &mut PhysicalBuddyAllocator<M> {
+    fn allocate_slab(&mut self, layout: Layout) -> *mut u8 {
+        self.try_allocate(layout).unwrap_or(ptr::null_mut())
+    }
+
+    fn deallocate_slab(&mut self, slab: *mut u8, layout: Layout) {
+        self.deallocate(slab, layout);
+    }
+}
+
+struct FreeObject {
+    next: *mut FreeObject
+}
+
+#[repr(C)]
+struct SlabHeader {
+    next: *mut SlabHeader,
+    previous: *mut SlabHeader,
+    free: *mut FreeObject, // List of free objects in this slab
+    used: usize,
+    capacity: usize
+}
+
+const _: () = assert!(mem::size_of::<SlabHeader>() <= SLAB_HEADER_SIZE, "Slab header does not fit");
+
+pub struct Cache {
+    pub object_size: usize,
+    pub slab_size: usize,
+
+    // Slabs that have at least one free object
+    partial: *mut SlabHeader,
+
+    pub slab_count: usize,
+    pub used_objects: usize
+}
+
+impl Cache {
+    const fn new(object_size: usize) -> Cache {
+        let slab_size = if object_size >= LARGE_OBJECT_SIZE { LARGE_SLAB_SIZE } else { SMALL_SLAB_SIZE };
+
+        Self { object_size, slab_size, partial: ptr::null_mut(), slab_count: 0, used_objects: 0 }
+    }
+
+    fn capacity(&self) -> usize {
+        (self.slab_size - SLAB_HEADER_SIZE) / self.object_size
+    }
+
+    unsafe fn push_partial(&mut self, slab: *mut SlabHeader) {
+        (*slab).previous = ptr::null_mut();
+        (*slab).next = self.partial;
+
+        if !self.partial.is_null() {
+            (*self.partial).previous = slab;
+        }
+
+        self.partial = slab;
+    }
+
+    unsafe fn remove_partial(&mut self, slab: *mut SlabHeader) {
+        let (previous, next) = ((*slab).previous, (*slab).next);
+
+        if !previous.is_null() {
+            (*previous).next = next;
+        } else {
+            self.partial = next;
+        }
+
+        if !next.is_null() {
+            (*next).previous = previous;
+        }
+
+        (*slab).next = ptr::null_mut();
+        (*slab).previous = ptr::null_mut();
+    }
+
+    // Slabs must be aligned to their size, so that we can find the header of an object by aligning its address
+    fn slab_layout(&self) -> Layout {
+        Layout::from_size_align(self.slab_size, self.slab_size).unwrap()
+    }
+
+    unsafe fn create_slab(&mut self, source: &mut impl SlabSource) -> Option<*mut SlabHeader> {
+        let slab = source.allocate_slab(self.slab_layout()) as *mut SlabHeader;
+
+        if slab.is_null() {
+            return None;
+        }
+
+        let capacity = self.capacity();
+        *slab = SlabHeader { next: ptr::null_mut(), previous: ptr::null_mut(), free: ptr::null_mut(), used: 0, capacity };
+
+        // Chain all the objects into the free list so that the first object is taken first
+        let objects = (slab as *mut u8).add(SLAB_HEADER_SIZE);
+
+        for index in (0..capacity).rev() {
+            let object = objects.add(index * self.object_size) as *mut FreeObject;
+            (*object).next = (*slab).free;
+            (*slab).free = object;
+        }
+
+        self.slab_count += 1;
+
+        debug_write_line!(
+            "Physical slab allocator: Created a slab for {} byte objects at {:p}", self.object_size, slab
+        );
+
+        Some(slab)
+    }
+
+    unsafe fn destroy_slab(&mut self, slab: *mut SlabHeader, source: &mut impl SlabSource) {
+        debug_write_line!(
+            "Physical slab allocator: Releasing a slab for {} byte objects at {:p}", self.object_size, slab
+        );
+
+        source.deallocate_slab(slab as *mut u8, self.slab_layout());
+
+        self.slab_count -= 1;
+    }
+
+    unsafe fn allocate(&mut self, source: &mut impl SlabSource) -> *mut u8 {
+        if self.partial.is_null() {
+            let Some(slab) = self.create_slab(source) else {
+                return ptr::null_mut();
+            };
+
+            self.push_partial(slab);
+        }
+
+        let slab = self.partial;
+        let object = (*slab).free;
+        assert!(!object.is_null(), "Partial slab did not have free objects");
+
+        (*slab).free = (*object).next;
+        (*slab).used += 1;
+        self.used_objects += 1;
+
+        // Full slabs are not tracked, they are found again through the objects when they are freed
+        if (*slab).used == (*slab).capacity {
+            self.remove_partial(slab);
+        }
+
+        object as *mut u8
+    }
+
+    unsafe fn deallocate(&mut self, address: *mut u8, source: &mut impl SlabSource) {
+        let slab = (address as usize & !(self.slab_size - 1)) as *mut SlabHeader;
+
+        let offset = address as usize - slab as usize;
+        assert!(
+            offset >= SLAB_HEADER_SIZE && (offset - SLAB_HEADER_SIZE).is_multiple_of(self.object_size),
+            "Physical slab allocator: Address does not point to an object"
+        );
+        assert!((*slab).used > 0, "Physical slab allocator: Slab has no allocated objects");
+
+        let was_full = (*slab).used == (*slab).capacity;
+
+        let object = address as *mut FreeObject;
+        (*object).next = (*slab).free;
+        (*slab).free = object;
+        (*slab).used -= 1;
+        self.used_objects -= 1;
+
+        if was_full {
+            self.push_partial(slab);
+        }
+
+        // Keep one slab around, so that allocating and freeing a single object does not bounce slabs
+        if (*slab).used == 0 && !(self.partial == slab && (*slab).next.is_null()) {
+            self.remove_partial(slab);
+            self.destroy_slab(slab, source);
+        }
+    }
+
+    // Releases the slab kept around for reuse and returns how many bytes were released
+    unsafe fn release_empty_slab(&mut self, source: &mut impl SlabSource) -> usize {
+        let slab = self.partial;
+
+        if slab.is_null() || (*slab).used != 0 {
+            return 0;
+        }
+
+        self.remove_partial(slab);
+        self.destroy_slab(slab, source);
+
+        self.slab_size
+    }
+}
+
+pub struct PhysicalSlabAllocator<S: SlabSource = KernelSlabSource> {
+    caches: [Cache; SIZE_CLASS_COUNT],
+    source: S
+}
+
+unsafe impl<S: SlabSource> Send for PhysicalSlabAllocator<S> {}
+
+impl Default for PhysicalSlabAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PhysicalSlabAllocator {
+    pub const fn new() -> PhysicalSlabAllocator {
+        Self::with_source(KernelSlabSource)
+    }
+
+    // Returns whether the specified layout should be served by this allocator instead of the buddy allocator
+    pub fn is_suitable(layout: Layout) -> bool {
+        layout.size() <= MAX_OBJECT_SIZE && layout.align() <= MAX_OBJECT_ALIGNMENT
+    }
+
+    pub fn get_cache_index(layout: Layout) -> usize {
+        // Objects are aligned to their size up to the header size, so the alignment can be handled by rounding up the size
+        let size = layout.size().max(layout.align()).max(MIN_OBJECT_SIZE).next_power_of_two();
+        (size.trailing_zeros() - MIN_OBJECT_SIZE.trailing_zeros()) as usize
+    }
+
+    // Returns a layout that any object of the size class satisfies
+    pub fn get_size_class_layout(index: usize) -> Layout {
+        let size = MIN_OBJECT_SIZE << index;
+        Layout::from_size_align(size, size.min(MAX_OBJECT_ALIGNMENT)).unwrap()
+    }
+
+    pub fn is_same_size_class(a: Layout, b: Layout) -> bool {
+        Self::get_cache_index(a) == Self::get_cache_index(b)
+    }
+}
+
+impl<S: SlabSource> PhysicalSlabAllocator<S> {
+    pub const fn with_source(source: S) -> PhysicalSlabAllocator<S> {
+        Self {
+            caches: [
+                Cache::new(16),
+                Cache::new(32),
+                Cache::new(64),
+                Cache::new(128),
+                Cache::new(256),
+                Cache::new(512),
+                Cache::new(1024),
+                Cache::new(2048)
+            ],
+            source
+        }
+    }
+
+    pub fn cache(&self, index: usize) -> &Cache {
+        &self.caches[index]
+    }
+
+    fn get_cache(&mut self, layout: Layout) -> (&mut Cache, &mut S) {
+        assert!(
+            <PhysicalSlabAllocator>::is_suitable(layout),
+            "Physical slab allocator: Layout is not suitable for slab allocation"
+        );
+
+        (&mut self.caches[<PhysicalSlabAllocator>::get_cache_index(layout)], &mut self.source)
+    }
+
+    // Returns null if there is no memory for a new slab
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (cache, source) = self.get_cache(layout);
+        unsafe { cache.allocate(source) }
+    }
+
+    /// # Safety
+    /// The address must have been returned by allocate with a layout of the same size class.
+    pub unsafe fn deallocate(&mut self, address: *mut u8, layout: Layout) {
+        let (cache, source) = self.get_cache(layout);
+        cache.deallocate(address, source)
+    }
+
+    // Releases the slabs the caches keep around for reuse, returns how many bytes were released
+    pub fn release_empty_slabs(&mut self) -> usize {
+        let source = &mut self.source;
+        self.caches.iter_mut().map(|cache| unsafe { cache.release_empty_slab(source) }).sum()
+    }
+}
+
+lazy_static! {
+    pub static ref instance: Mutex<PhysicalSlabAllocator> = {
+        Mutex::new(PhysicalSlabAllocator::new())
+    };
+}
+
+// Reclaim callback that releases the empty slabs the caches keep around
+pub fn reclaim(_bytes: usize) -> usize {
+    // Note: We might be reclaiming while creating a slab, in which case the caches are locked
+    let Some(mut allocator) = instance.try_lock() else {
+        return 0;
+    };
+
+    allocator.release_empty_slabs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::memory::physical_buddy_allocator::arena::Arena;
+
+    fn layout(size: usize, alignment: usize) -> Layout {
+        Layout::from_size_align(size, alignment).unwrap()
+    }
+
+    #[test]
+    fn objects_are_aligned_and_reused() {
+        let mut arena = Arena::with_available_memory();
+        let mut allocator = PhysicalSlabAllocator::with_source(&mut arena.allocator);
+
+        let objects: Vec<*mut u8> = (0..8).map(|_| allocator.allocate(layout(24, 8))).collect();
+
+        for object in objects.iter() {
+            assert!(!object.is_null());
+            assert_eq!(*object as usize % 32, 0);
+        }
+
+        unsafe { allocator.deallocate(objects[3], layout(24, 8)) };
+        assert_eq!(allocator.allocate(layout(20, 4)), objects[3]);
+        assert_eq!(allocator.cache(1).used_objects, 8);
+    }
+
+    #[test]
+    fn freeing_all_objects_keeps_one_slab() {
+        let mut arena = Arena::with_available_memory();
+        let mut allocator = PhysicalSlabAllocator::with_source(&mut arena.allocator);
+
+        // More objects than a single slab holds, so that a second slab is created
+        let count = allocator.cache(0).capacity() + 1;
+        let objects: Vec<*mut u8> = (0..count).map(|_| allocator.allocate(layout(16, 16))).collect();
+        assert_eq!(allocator.cache(0).slab_count, 2);
+
+        for object in objects {
+            unsafe { allocator.deallocate(object, layout(16, 16)) };
+        }
+
+        assert_eq!(allocator.cache(0).slab_count, 1);
+        assert_eq!(allocator.cache(0).used_objects, 0);
+    }
+
+    #[test]
+    fn reclaiming_releases_the_kept_slabs() {
+        let mut arena = Arena::with_available_memory();
+        let total = arena.count_l0_slabs();
+
+        {
+            let mut allocator = PhysicalSlabAllocator::with_source(&mut arena.allocator);
+
+            let small = allocator.allocate(layout(64, 64));
+            let large = allocator.allocate(layout(MAX_OBJECT_SIZE, 8));
+
+            // Slabs with live objects are not released
+            assert_eq!(allocator.release_empty_slabs(), 0);
+
+            unsafe {
+                allocator.deallocate(small, layout(64, 64));
+                allocator.deallocate(large, layout(MAX_OBJECT_SIZE, 8));
+            }
+
+            assert_eq!(allocator.release_empty_slabs(), SMALL_SLAB_SIZE + LARGE_SLAB_SIZE);
+            assert_eq!(allocator.release_empty_slabs(), 0);
+        }
+
+        assert_eq!(arena.count_l0_slabs(), total);
+    }
+}
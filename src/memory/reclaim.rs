@@ -0,0 +1,67 @@
+use core::{alloc::Layout, ptr};
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::debug_write_line;
+
+use super::physical_buddy_allocator;
+
+pub const MAX_RECLAIM_CALLBACK_COUNT: usize = 16;
+
+// Releases memory that can be recreated later, such as caches, and returns how many bytes were released.
+// Note: Callbacks may run in the middle of an allocation, so they must not block on allocator locks.
+pub type ReclaimCallback = fn(bytes: usize) -> usize;
+
+lazy_static! {
+    static ref callbacks: Mutex<[Option<ReclaimCallback>; MAX_RECLAIM_CALLBACK_COUNT]> = {
+        Mutex::new([None; MAX_RECLAIM_CALLBACK_COUNT])
+    };
+}
+
+pub fn register(callback: ReclaimCallback) {
+    let mut slots = callbacks.lock();
+    let slot = slots.iter_mut().find(|slot| slot.is_none()).expect("Reclaim: Too many reclaim callbacks");
+    *slot = Some(callback);
+}
+
+pub fn unregister(callback: ReclaimCallback) {
+    let mut slots = callbacks.lock();
+
+    for slot in slots.iter_mut() {
+        if slot.is_some_and(|registered| ptr::fn_addr_eq(registered, callback)) {
+            *slot = None;
+        }
+    }
+}
+
+// Asks all the registered callbacks to release memory and returns how many bytes were released
+pub fn reclaim(bytes: usize) -> usize {
+    // Copy the callbacks, so that the callbacks can register and unregister callbacks themselves
+    let registered = *callbacks.lock();
+    let mut reclaimed = 0;
+
+    for callback in registered.iter().flatten() {
+        reclaimed += callback(bytes);
+    }
+
+    debug_write_line!("Reclaim: Reclaimed {} byte(s) out of {} requested byte(s)", reclaimed, bytes);
+
+    reclaimed
+}
+
+pub fn report_out_of_memory(layout: Layout) {
+    debug_write_line!(
+        "Reclaim: Out of memory while allocating {} byte(s) with alignment of {} byte(s)",
+        layout.size(),
+        layout.align()
+    );
+
+    // The allocation may have failed while the allocator was locked, in which case we can't inspect it
+    match physical_buddy_allocator::instance.try_lock() {
+        Some(mut allocator) => allocator.statistics().print(),
+        None => {
+            debug_write_line!("Reclaim: Physical buddy allocator is locked, can not report its state");
+        }
+    }
+}
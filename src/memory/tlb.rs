@@ -0,0 +1,181 @@
+use core::{hint, sync::atomic::{AtomicUsize, Ordering}};
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::{interrupts::apic, low::processor::{self, Processor}};
+
+use super::{VirtualAddress, SMALL_PAGE_SIZE};
+
+extern "C" {
+    fn flush_tlb();
+    fn flush_tlb_local(address: u64);
+}
+
+// Invalidating more pages one by one than this is slower than reloading the whole TLB
+pub const MAX_FLUSH_SET_SIZE: usize = 32;
+
+pub const TLB_SHOOTDOWN_INTERRUPT_NUMBER: u64 = 0xfd;
+
+// Collects the pages whose translations changed, so that they can be invalidated at once
+#[derive(Clone, Copy)]
+pub struct FlushSet {
+    addresses: [VirtualAddress; MAX_FLUSH_SET_SIZE],
+    count: usize,
+    full: bool // Too many pages were added, so the whole TLB is flushed instead
+}
+
+impl Default for FlushSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FlushSet {
+    pub const fn new() -> FlushSet {
+        Self { addresses: [VirtualAddress::null(); MAX_FLUSH_SET_SIZE], count: 0, full: false }
+    }
+
+    pub const fn all() -> FlushSet {
+        Self { addresses: [VirtualAddress::null(); MAX_FLUSH_SET_SIZE], count: 0, full: true }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0 && !self.full
+    }
+
+    pub fn add(&mut self, address: VirtualAddress) {
+        if self.full {
+            return;
+        }
+
+        if self.count == MAX_FLUSH_SET_SIZE {
+            self.full = true;
+            return;
+        }
+
+        self.addresses[self.count] = address.align(SMALL_PAGE_SIZE);
+        self.count += 1;
+    }
+
+    pub fn add_range(&mut self, start: VirtualAddress, size: usize) {
+        // Note: Adding a page at a time would take long for large ranges that end up flushing everything anyway
+        if size / SMALL_PAGE_SIZE > MAX_FLUSH_SET_SIZE - self.count {
+            self.full = true;
+            return;
+        }
+
+        for offset in (0..size).step_by(SMALL_PAGE_SIZE) {
+            self.add(VirtualAddress::new(start.value() + offset));
+        }
+    }
+
+    // Invalidates the pages on this processor only
+    fn flush_local(&self) {
+        unsafe {
+            if self.full {
+                flush_tlb();
+                return;
+            }
+
+            for address in &self.addresses[..self.count] {
+                flush_tlb_local(address.value() as u64);
+            }
+        }
+    }
+
+    // Invalidates the pages on all the processors
+    pub fn flush(&self) {
+        if self.is_empty() {
+            return;
+        }
+
+        self.flush_local();
+        shootdown(self);
+    }
+}
+
+pub fn flush_page(address: VirtualAddress) {
+    let mut flush_set = FlushSet::new();
+    flush_set.add(address);
+    flush_set.flush();
+}
+
+pub fn flush_range(start: VirtualAddress, size: usize) {
+    let mut flush_set = FlushSet::new();
+    flush_set.add_range(start, size);
+    flush_set.flush();
+}
+
+pub fn flush_all() {
+    FlushSet::all().flush();
+}
+
+lazy_static! {
+    // Held by the processor that is shooting down, so that only one request is in flight at a time
+    static ref shootdown_lock: Mutex<()> = Mutex::new(());
+    static ref shootdown_request: Mutex<FlushSet> = Mutex::new(FlushSet::new());
+}
+
+// Number of processors that have not yet handled the current shootdown request
+static PENDING_SHOOTDOWN_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// Identifies the current shootdown request, so that each processor handles it only once
+static SHOOTDOWN_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+pub fn shootdown_generation() -> usize {
+    SHOOTDOWN_GENERATION.load(Ordering::Acquire)
+}
+
+// Asks the other processors to invalidate the pages and waits until they have done so,
+// because the pages may be reused as soon as this returns.
+// Note: The processors may wait with interrupts disabled, so they handle the request in flight while waiting.
+// Otherwise, two processors that shoot down at the same time would wait for each other forever.
+fn shootdown(flush_set: &FlushSet) {
+    let other_count = processor::online_count().saturating_sub(1);
+
+    if other_count == 0 {
+        return;
+    }
+
+    let _guard = loop {
+        if let Some(guard) = shootdown_lock.try_lock() {
+            break guard;
+        }
+
+        handle_shootdown();
+        hint::spin_loop();
+    };
+
+    *shootdown_request.lock() = *flush_set;
+
+    // Note: The count must be set before the generation changes, because the other processors may handle the request
+    // as soon as they see the new generation, even before the interrupt arrives
+    PENDING_SHOOTDOWN_COUNT.store(other_count, Ordering::Release);
+    Processor::current().shootdown_generation = SHOOTDOWN_GENERATION.fetch_add(1, Ordering::AcqRel) + 1;
+
+    apic::send_interrupt_to_others(TLB_SHOOTDOWN_INTERRUPT_NUMBER as u8);
+
+    while PENDING_SHOOTDOWN_COUNT.load(Ordering::Acquire) != 0 {
+        handle_shootdown();
+        hint::spin_loop();
+    }
+}
+
+// Called on the processors that received the shootdown interrupt, and on the ones that wait to shoot down themselves.
+// Note: The interrupt may arrive after the request was handled while waiting, in which case there is nothing to do.
+pub fn handle_shootdown() {
+    let processor = Processor::current();
+    let generation = SHOOTDOWN_GENERATION.load(Ordering::Acquire);
+
+    if processor.shootdown_generation == generation {
+        return;
+    }
+
+    processor.shootdown_generation = generation;
+
+    let flush_set = *shootdown_request.lock();
+    flush_set.flush_local();
+
+    PENDING_SHOOTDOWN_COUNT.fetch_sub(1, Ordering::AcqRel);
+}